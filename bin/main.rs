@@ -37,11 +37,17 @@ extern crate awatchlog;
 
 use getopts::Options;
 use std::env;
+use std::process;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
+    if args.len() > 1 && "encrypt-credentials" == args[1] {
+        encrypt_credentials(&program, &args[2..]);
+        return;
+    }
+
     let mut opts = Options::new();
     opts.optopt(
         "c",
@@ -59,7 +65,7 @@ fn main() {
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+        Err(f) => { exit_with_error(f.to_string()) }
     };
 
     if matches.opt_present("h") {
@@ -70,10 +76,58 @@ fn main() {
     let config_file = matches.opt_str("c");
     let credentials_file = matches.opt_str("credentials");
 
-    awatchlog::run(config_file, credentials_file);
+    if let Err(why) = awatchlog::run(config_file, credentials_file) {
+        exit_with_error(why.to_string());
+    }
+}
+
+fn encrypt_credentials(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt(
+        "i",
+        "input",
+        "plaintext credentials file in TOML (default: /etc/awatchlog/credentials.toml)",
+        "Input"
+    );
+    opts.reqopt(
+        "o",
+        "output",
+        "encrypted credentials file to write",
+        "Output"
+    );
+    opts.optflag("h", "help", "print this help menu");
+
+    let brief = format!(
+        "Usage: {} encrypt-credentials [options]\n\n\
+        The passphrase is read from the file named by AWATCHLOG_CREDENTIALS_KEY_FILE\n\
+        or from AWATCHLOG_CREDENTIALS_KEY.",
+        program
+    );
+
+    if args.iter().any(|arg| "-h" == arg || "--help" == arg) {
+        print!("{}", opts.usage(&brief));
+        return;
+    }
+
+    let matches = match opts.parse(args) {
+        Ok(m) => { m }
+        Err(f) => { exit_with_error(f.to_string()) }
+    };
+
+    let input = matches.opt_str("i").unwrap_or("/etc/awatchlog/credentials.toml".to_string());
+    let output = matches.opt_str("o").unwrap();
+
+    if let Err(why) = awatchlog::encrypt_credentials(input, output) {
+        exit_with_error(why.to_string());
+    }
+}
+
+fn exit_with_error(message: String) -> ! {
+    eprintln!("awatchlog: {}", message);
+    process::exit(1);
 }
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!("Usage: {} [options]\n       {} encrypt-credentials [options]", program, program);
     print!("{}", opts.usage(&brief));
 }
@@ -30,81 +30,384 @@
 // OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate shuteye;
 extern crate sha1;
+extern crate hyper;
+extern crate regex;
+extern crate glob;
+extern crate flate2;
+extern crate zstd;
+extern crate libc;
+extern crate ring;
+extern crate base64;
 
 extern crate rusoto_credential;
 extern crate rusoto_logs;
 extern crate rusoto_core;
+extern crate rusoto_sts;
 
-use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::mpsc::Sender;
 use std::thread;
-use rusoto_credential::{DefaultCredentialsProvider};
+use std::time::Duration;
+use shuteye::sleep;
+use rusoto_credential::{AutoRefreshingProvider, StaticProvider};
 use rusoto_core::{default_tls_client, Region};
 use rusoto_logs::{
     CloudWatchLogs,
     CloudWatchLogsClient,
 };
 
+mod error;
 mod logger;
 mod config;
+pub use error::Error;
 use config::configuration;
-use config::configuration::{AwatchLogConfig};
+use config::configuration::{AwatchLogConfig, ConfigGeneral, ConfigLogFile};
 use config::credentials;
+use config::discovery;
+use config::endpoint::{Endpoint, EndpointDispatcher};
+use config::placeholder;
+use config::region;
+use logger::retry::RetryPolicy;
 
-pub fn run(config_file: Option<String>, credentials_file: Option<String>) {
-    let config: AwatchLogConfig = configuration::parse(config_file);
+const DEFAULT_ROLE_SESSION_NAME: &'static str = "awatchlog";
 
-    // TODO must auto detect region by using instance metadata
-    // use config::discovery::metadata
+/// Watch the logfiles of the configuration
+///
+/// Each logfile is watched by its own thread, forever. A file which cannot
+/// be read or sent is watched again after `rescan_interval`, a file matching
+/// a pattern is dropped until the next scan picks it up. Only configuration
+/// errors and a credentials file which cannot be loaded stop the agent.
+pub fn run(config_file: Option<String>, credentials_file: Option<String>) -> Result<(), Error> {
+    let config: AwatchLogConfig = configuration::parse(config_file)?;
+    credentials::check_permissions(credentials_file.as_ref(), config.general.strict_permissions)?;
 
-    let region = match Region::from_str(&config.general.region) {
-        Ok(region) => region,
-        Err(_) => Region::UsEast1,
-    };
+    let metadata: Option<discovery::Metadata> = discovery::metadata(config.general.metadata_endpoint.to_owned());
+    match metadata {
+        Some(ref metadata) => println!("Instance metadata: {:?}", metadata),
+        None => println!("Instance metadata not available"),
+    }
+
+    let region: Region = region::resolve(config.general.region.as_ref(), metadata.as_ref())
+        .map_err(Error::Config)?;
 
     // TODO check if pid already up
     println!("PID FILE: {}", config.general.pid_file);
 
-    let mut threads: Vec<std::thread::JoinHandle<_>> = vec![];
+    let rescan_interval = Duration::new(config.general.rescan_interval_secs, 0);
+    let placeholder_values = placeholder::Values::new(metadata.as_ref(), region.to_string());
+
+    // Fail before watching anything when a placeholder has no value
+    for logfile in &config.logfile {
+        expand_names(&mut logfile.clone(), &placeholder_values)?;
+    }
+    let retry = RetryPolicy {
+        max_attempts: config.general.retry_max_attempts,
+        max_delay: Duration::from_millis(config.general.retry_max_delay_ms),
+    };
+
+    let (sender, receiver) = mpsc::channel::<Report>();
     for logfile in config.logfile {
-        let region_clone = region.clone();
+        let target_clone = target(&region, &logfile, &config.general)?;
+        let role_clone = assume_role(&region, &logfile, &config.general);
         let credentials_file_clone = credentials_file.clone();
+        let placeholder_values_clone = placeholder_values.clone();
+        let sender_clone = sender.clone();
 
-        let handle = thread::spawn(move || {
-            let client: Box<CloudWatchLogs> = get_client(region_clone, credentials_file_clone);
-            logger::watch(logfile, &client);
-        });
+        if logger::files::is_pattern(&logfile.file) {
+            // Only fails on configuration errors, each file is handled by its own thread
+            spawn_watcher(sender.clone(), logfile.file.to_owned(), None, move || {
+                watch_pattern(
+                    logfile.clone(),
+                    target_clone.clone(),
+                    credentials_file_clone.clone(),
+                    role_clone.clone(),
+                    placeholder_values_clone.clone(),
+                    retry,
+                    rescan_interval,
+                    sender_clone.clone()
+                )
+            });
+        } else {
+            spawn_watcher(sender.clone(), logfile.file.to_owned(), Some(rescan_interval), move || {
+                let mut logfile = logfile.clone();
+                expand_names(&mut logfile, &placeholder_values_clone)
+                    .and_then(|_| watch_file(
+                        logfile,
+                        target_clone.clone(),
+                        credentials_file_clone.clone(),
+                        role_clone.clone(),
+                        retry,
+                        false
+                    ))
+            });
+        }
+    }
 
-        threads.push(handle);
+    // Only the threads hold a sender now, the loop ends once they are all gone.
+    // Watchers report only the errors which stop the agent.
+    drop(sender);
+    for report in receiver {
+        match report {
+            Ok(result) => result?,
+            Err(payload) => panic::resume_unwind(payload),
+        }
     }
 
-    for handle in threads {
-        handle.join();
+    return Ok(());
+}
+
+// How the watcher of a logfile ended, or the payload of its panic
+type Report = thread::Result<Result<(), Error>>;
+
+// Run a watcher of the file in its own thread and send how it ended,
+// a panic is sent to be raised again by the main thread.
+//
+// An error which does not stop the agent is logged, then the watcher is run
+// again after `retry_interval`, or the file is dropped without one.
+fn spawn_watcher<F>(sender: Sender<Report>, file: String, retry_interval: Option<Duration>, watcher: F)
+    where F: Fn() -> Result<(), Error> + Send + 'static
+{
+    thread::spawn(move || {
+        let report: Report = panic::catch_unwind(AssertUnwindSafe(|| loop {
+            match watcher() {
+                Err(ref why) if !why.is_fatal() => match retry_interval {
+                    Some(interval) => {
+                        println!("ERROR: watching {} : {}, retry in {} seconds", file, why, interval.as_secs());
+                        sleep(interval);
+                    },
+                    None => {
+                        println!("ERROR: stop watching {} : {}", file, why);
+                        return Ok(());
+                    },
+                },
+                result => return result,
+            }
+        }));
+
+        sender.send(report).ok();
+    });
+}
+
+/// Write an encrypted copy of a plaintext credentials file
+///
+/// The passphrase is read from `AWATCHLOG_CREDENTIALS_KEY_FILE` or `AWATCHLOG_CREDENTIALS_KEY`.
+pub fn encrypt_credentials(input: String, output: String) -> Result<(), Error> {
+    credentials::encrypt_file(&input, &output)?;
+    println!("Encrypted credentials written to {}", output);
+
+    return Ok(());
+}
+
+/// Watch a single file, its names already expanded
+///
+/// Return only when it cannot be watched any more, or once it has been
+/// removed when `stop_when_removed` is set.
+fn watch_file(
+    logfile: ConfigLogFile,
+    target: Target,
+    credentials_file: Option<String>,
+    role: Option<credentials::AssumeRole>,
+    retry: RetryPolicy,
+    stop_when_removed: bool
+) -> Result<(), Error> {
+    return get_client(target, credentials_file, role)
+        .and_then(|client| logger::watch(logfile, &client, retry, stop_when_removed));
+}
+
+/// Watch every file matching the glob pattern of the logfile
+///
+/// The pattern is expanded again every `rescan_interval` and a thread is
+/// spawned for each new file, so files created later are picked up. The
+/// thread of a file stops once it has been removed, or when it cannot be
+/// read or sent, until a later scan picks it up again. A file whose stream
+/// is already written by another one is skipped. The result of each file is
+/// reported through `sender`.
+fn watch_pattern(
+    logfile: ConfigLogFile,
+    target: Target,
+    credentials_file: Option<String>,
+    role: Option<credentials::AssumeRole>,
+    placeholder_values: placeholder::Values,
+    retry: RetryPolicy,
+    rescan_interval: Duration,
+    sender: Sender<Report>
+) -> Result<(), Error> {
+    // Stream written by each watched file, a file is removed once its thread ends
+    let watched: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+    let mut skipped: HashSet<String> = HashSet::new();
+
+    loop {
+        for file in logger::files::expand(&logfile.file)? {
+            if watched.lock().unwrap().contains_key(&file) {
+                continue;
+            }
+
+            let mut matched_logfile: ConfigLogFile = logfile.clone();
+            matched_logfile.file = file.to_owned();
+            expand_names(&mut matched_logfile, &placeholder_values)?;
+
+            let stream: String = format!("{}/{}", matched_logfile.log_group_name, matched_logfile.log_stream_name);
+            {
+                let mut watched_streams = watched.lock().unwrap();
+                let writer: Option<String> = watched_streams.iter()
+                    .find(|&(_, watched_stream)| *watched_stream == stream)
+                    .map(|(watched_file, _)| watched_file.to_owned());
+
+                if let Some(writer) = writer {
+                    if skipped.insert(file.to_owned()) {
+                        println!("WARNING: skip {} matching {}, its stream {} is already written by {}",
+                                 file, logfile.file, stream, writer);
+                    }
+                    continue;
+                }
+
+                watched_streams.insert(file.to_owned(), stream);
+            }
+
+            skipped.remove(&file);
+            println!("File {} match pattern {}", file, logfile.file);
+
+            let target_clone = target.clone();
+            let credentials_file_clone = credentials_file.clone();
+            let role_clone = role.clone();
+            let watched_clone = watched.clone();
+
+            spawn_watcher(sender.clone(), file.to_owned(), None, move || {
+                let result = watch_file(
+                    matched_logfile.clone(),
+                    target_clone.clone(),
+                    credentials_file_clone.clone(),
+                    role_clone.clone(),
+                    retry,
+                    true
+                );
+
+                // Picked up again by the next scan if it comes back
+                watched_clone.lock().unwrap().remove(&file);
+
+                result
+            });
+        }
+
+        sleep(rescan_interval);
     }
 }
 
-fn get_client(region: Region, credentials_file: Option<String>) -> Box<CloudWatchLogs> {
-    return match credentials::parse(credentials_file) {
-        None => {
-            let credentials = DefaultCredentialsProvider::new().unwrap();
-            Box::new(CloudWatchLogsClient::new(
-                default_tls_client().unwrap(),
-                credentials,
-                region
-            ))
-        },
-        Some(credentials) => {
-            Box::new(CloudWatchLogsClient::new(
-                default_tls_client().unwrap(),
-                credentials,
-                region
-            ))
+// Region and optional custom endpoint of the client of a logfile
+#[derive(Clone)]
+struct Target {
+    region: Region,
+    endpoint: Option<Endpoint>,
+}
+
+/// Where the client of a logfile sends its requests
+///
+/// A custom `endpoint_url` of the logfile or of the general section, such
+/// as a VPC endpoint or a local stand-in, replaces the regional endpoint.
+/// Requests are still signed for the region of the agent.
+fn target(region: &Region, logfile: &ConfigLogFile, general: &ConfigGeneral) -> Result<Target, Error> {
+    let endpoint: Option<Endpoint> = match logfile.endpoint_url.as_ref().or(general.endpoint_url.as_ref()) {
+        Some(endpoint_url) => {
+            println!("Endpoint of {} : {}", logfile.file, endpoint_url);
+            match Endpoint::parse(endpoint_url) {
+                Ok(endpoint) => Some(endpoint),
+                Err(why) => return Err(Error::Config(format!("Invalid endpoint_url {} : {}", endpoint_url, why))),
+            }
         },
+        None => None,
+    };
+
+    return Ok(Target {
+        region: region.clone(),
+        endpoint: endpoint,
+    });
+}
+
+/// Role assumed by the client of a logfile
+///
+/// The role of the logfile overrides the general one, STS is called in the
+/// region of the agent even when a custom `endpoint_url` is set.
+fn assume_role(region: &Region, logfile: &ConfigLogFile, general: &ConfigGeneral) -> Option<credentials::AssumeRole> {
+    let role_arn: &String = match logfile.role_arn.as_ref().or(general.role_arn.as_ref()) {
+        Some(role_arn) => role_arn,
+        None => return None,
+    };
+
+    println!("Role of {} : {}", logfile.file, role_arn);
+
+    return Some(credentials::AssumeRole {
+        role_arn: role_arn.to_owned(),
+        external_id: logfile.external_id.as_ref().or(general.external_id.as_ref()).cloned(),
+        role_session_name: logfile.role_session_name.as_ref()
+            .or(general.role_session_name.as_ref())
+            .cloned()
+            .unwrap_or(DEFAULT_ROLE_SESSION_NAME.to_string()),
+        region: region.clone(),
+    });
+}
+
+/// Replace the placeholders of the group and stream names
+fn expand_names(logfile: &mut ConfigLogFile, values: &placeholder::Values) -> Result<(), Error> {
+    logfile.log_group_name = match placeholder::expand(&logfile.log_group_name, values, &logfile.file) {
+        Ok(name) => name,
+        Err(why) => return Err(Error::Config(format!("Cannot expand log_group_name of {} : {}", logfile.file, why))),
+    };
+
+    logfile.log_stream_name = match placeholder::expand(&logfile.log_stream_name, values, &logfile.file) {
+        Ok(name) => name,
+        Err(why) => return Err(Error::Config(format!("Cannot expand log_stream_name of {} : {}", logfile.file, why))),
+    };
+
+    return Ok(());
+}
+
+fn get_client(
+    target: Target,
+    credentials_file: Option<String>,
+    role: Option<credentials::AssumeRole>
+) -> Result<Box<CloudWatchLogs>, Error> {
+    let mut provider: credentials::Credentials = credentials::parse(credentials_file)?;
+    if let Some(role) = role {
+        provider = credentials::Credentials::AssumeRole(Box::new(
+            credentials::AssumeRoleProvider::new(provider, role)?
+        ));
     }
+
+    // The first fetch may fail for a while, such as an STS or instance
+    // metadata timeout, the watcher of the file tries again later
+    let credentials = match AutoRefreshingProvider::with_refcell(provider) {
+        Ok(credentials) => credentials,
+        Err(why) => return Err(Error::Aws(format!("Cannot get AWS credentials : {}", why))),
+    };
+
+    let dispatcher = match default_tls_client() {
+        Ok(dispatcher) => dispatcher,
+        Err(why) => return Err(Error::Aws(format!("Cannot create CloudWatch Logs client : {}", why))),
+    };
+
+    return Ok(match target.endpoint {
+        Some(endpoint) => Box::new(CloudWatchLogsClient::new(
+            EndpointDispatcher::new(dispatcher, credentials, endpoint, target.region.clone()),
+            // Only signs the request the dispatcher signs again
+            StaticProvider::new_minimal(String::new(), String::new()),
+            target.region
+        )),
+        None => Box::new(CloudWatchLogsClient::new(
+            dispatcher,
+            credentials,
+            target.region
+        )),
+    });
 }
\ No newline at end of file
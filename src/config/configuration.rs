@@ -32,8 +32,17 @@
 
 extern crate toml;
 
+use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
+use chrono::{FixedOffset, Local, TimeZone, NaiveDateTime};
+use chrono_tz::Tz;
+use serde::{de, Deserialize, Deserializer};
+use glob;
 use config;
+use config::endpoint::Endpoint;
+use error::Error;
+use logger::files;
 
 const DEFAULT_CONFIG_PATH: &'static str = "/usr/share/awatchlog/config.toml";
 
@@ -41,15 +50,213 @@ const DEFAULT_CONFIG_PATH: &'static str = "/usr/share/awatchlog/config.toml";
 pub struct ConfigGeneral {
     pub pid_file: String,
     pub state_path: String,
-    pub region: String,
+    pub region: Option<String>,
+    #[serde(default = "default_rescan_interval_secs")]
+    pub rescan_interval_secs: u64,
+    /// Instance metadata service to query instead of the EC2 one
+    pub metadata_endpoint: Option<String>,
+    /// CloudWatch Logs endpoint to use instead of the regional one
+    pub endpoint_url: Option<String>,
+    /// Role assumed with STS to write the logs, e.g. into another account
+    pub role_arn: Option<String>,
+    pub external_id: Option<String>,
+    pub role_session_name: Option<String>,
+    /// Refuse a credentials or key file readable by others instead of warning,
+    /// off by default so that existing installations keep starting
+    #[serde(default = "default_strict_permissions")]
+    pub strict_permissions: bool,
+    /// Attempts to send a batch failing transiently, the first one included,
+    /// the file is then read again from its saved offset
+    #[serde(default = "default_retry_max_attempts")]
+    pub retry_max_attempts: u32,
+    /// Longest delay between two attempts
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
 }
 
-#[derive(Deserialize)]
+fn default_rescan_interval_secs() -> u64 {
+    60
+}
+
+fn default_strict_permissions() -> bool {
+    false
+}
+
+fn default_retry_max_attempts() -> u32 {
+    8
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    30000
+}
+
+/// Timestamp given to a line which does not start with a date
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFallback {
+    /// Reuse the timestamp of the previous event
+    Previous,
+    /// Use the time the line has been read
+    Now,
+}
+
+impl Default for TimestampFallback {
+    fn default() -> TimestampFallback {
+        TimestampFallback::Previous
+    }
+}
+
+/// Built-in rules to group stack traces into a single event
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MultiLinePreset {
+    Java,
+    Python,
+    GoPanic,
+    Ruby,
+    Dotnet,
+    /// Any line starting with a whitespace continues the previous one
+    Indented,
+}
+
+/// Time zone of the timestamps written in a log file
+///
+/// Accept `UTC`, `Local`, a fixed offset such as `+02:00` or an IANA name
+/// such as `Europe/Paris`.
+#[derive(Clone, Copy, Debug)]
+pub enum LogTimeZone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl LogTimeZone {
+    /// Convert a date written in this time zone to UTC milliseconds
+    ///
+    /// Return None when the local date does not exist (DST gap),
+    /// the earliest candidate is taken when it is ambiguous.
+    pub fn timestamp_millis(&self, local: &NaiveDateTime) -> Option<i64> {
+        let utc: NaiveDateTime = match *self {
+            LogTimeZone::Utc => local.to_owned(),
+            LogTimeZone::Local => match Local.from_local_datetime(local).earliest() {
+                Some(datetime) => datetime.naive_utc(),
+                None => return None,
+            },
+            LogTimeZone::Fixed(offset) => match offset.from_local_datetime(local).earliest() {
+                Some(datetime) => datetime.naive_utc(),
+                None => return None,
+            },
+            LogTimeZone::Named(tz) => match tz.from_local_datetime(local).earliest() {
+                Some(datetime) => datetime.naive_utc(),
+                None => return None,
+            },
+        };
+
+        return Some(utc.timestamp() * 1000 + utc.timestamp_subsec_millis() as i64);
+    }
+}
+
+impl Default for LogTimeZone {
+    fn default() -> LogTimeZone {
+        LogTimeZone::Utc
+    }
+}
+
+impl fmt::Display for LogTimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LogTimeZone::Utc => write!(f, "UTC"),
+            LogTimeZone::Local => write!(f, "Local"),
+            LogTimeZone::Fixed(offset) => write!(f, "{}", offset),
+            LogTimeZone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+impl FromStr for LogTimeZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogTimeZone, String> {
+        match s {
+            "UTC" | "utc" | "Z" => return Ok(LogTimeZone::Utc),
+            "Local" | "local" => return Ok(LogTimeZone::Local),
+            _ => {},
+        }
+
+        if s.starts_with('+') || s.starts_with('-') {
+            return parse_fixed_offset(s)
+                .map(LogTimeZone::Fixed)
+                .ok_or(format!("Invalid time zone offset {}, expected format is +HH:MM", s));
+        }
+
+        return s.parse::<Tz>()
+            .map(LogTimeZone::Named)
+            .map_err(|_| format!("Unknown time zone {}", s));
+    }
+}
+
+impl<'de> Deserialize<'de> for LogTimeZone {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+// Parse offset written as +HH, +HHMM or +HH:MM
+fn parse_fixed_offset(s: &str) -> Option<FixedOffset> {
+    let sign: i32 = if s.starts_with('-') { -1 } else { 1 };
+    let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let (hours, minutes): (i32, i32) = match digits.len() {
+        2 => (digits.parse().unwrap(), 0),
+        4 => (digits[..2].parse().unwrap(), digits[2..].parse().unwrap()),
+        _ => return None,
+    };
+
+    if minutes >= 60 {
+        return None;
+    }
+
+    return FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60));
+}
+
+#[derive(Deserialize, Clone)]
 pub struct ConfigLogFile {
     pub file: String,
     pub log_group_name: String,
     pub log_stream_name: String,
     pub datetime_format: String,
+    #[serde(default)]
+    pub timestamp_fallback: TimestampFallback,
+    #[serde(default)]
+    pub time_zone: LogTimeZone,
+    pub multi_line_start_pattern: Option<String>,
+    pub multi_line_preset: Option<MultiLinePreset>,
+    #[serde(default = "default_multi_line_flush_timeout_ms")]
+    pub multi_line_flush_timeout_ms: u64,
+    /// Number of leading bytes hashed to recognize the file across restarts
+    pub fingerprint_size: Option<u64>,
+    /// Glob matching the rotated files, e.g. `/var/log/app.log.*`
+    pub rotated_pattern: Option<String>,
+    /// File receiving the events refused by CloudWatch Logs, kept in the
+    /// states path when not given
+    pub dead_letter_file: Option<String>,
+    /// CloudWatch Logs endpoint of this file, overrides the general one
+    pub endpoint_url: Option<String>,
+    /// Role assumed for this file, overrides the general one
+    pub role_arn: Option<String>,
+    pub external_id: Option<String>,
+    pub role_session_name: Option<String>,
+}
+
+fn default_multi_line_flush_timeout_ms() -> u64 {
+    5000
 }
 
 #[derive(Deserialize)]
@@ -58,19 +265,62 @@ pub struct AwatchLogConfig {
     pub logfile: Vec<ConfigLogFile>,
 }
 
-pub fn parse(file: Option<String>) -> AwatchLogConfig {
+pub fn parse(file: Option<String>) -> Result<AwatchLogConfig, Error> {
     let path: String = if let Some(file_path) = file {
         file_path
     } else {
         let default_path = Path::new(&DEFAULT_CONFIG_PATH);
 
         if false == default_path.exists() {
-            panic!("No configuration file found in default path {}\nYou can specify path using -c option", DEFAULT_CONFIG_PATH);
+            return Err(Error::Config(format!(
+                "No configuration file found in default path {}\nYou can specify path using -c option",
+                DEFAULT_CONFIG_PATH
+            )));
         }
 
         DEFAULT_CONFIG_PATH.to_string()
     };
 
-    let content = config::parser::get_file_content(path);
-    return toml::from_str(&content).unwrap();
+    let content = config::parser::get_file_content(path.to_owned())?;
+    let config: AwatchLogConfig = match toml::from_str(&content) {
+        Ok(config) => config,
+        Err(why) => return Err(Error::Config(format!("Invalid configuration file {} : {}", path, why))),
+    };
+
+    if 0 == config.general.retry_max_attempts {
+        return Err(Error::Config("retry_max_attempts must be at least 1".to_string()));
+    }
+
+    let endpoint_urls = config.logfile.iter()
+        .filter_map(|logfile| logfile.endpoint_url.as_ref())
+        .chain(config.general.endpoint_url.as_ref());
+    for endpoint_url in endpoint_urls {
+        if let Err(why) = Endpoint::parse(endpoint_url) {
+            return Err(Error::Config(format!("Invalid endpoint_url {} : {}", endpoint_url, why)));
+        }
+    }
+
+    for logfile in &config.logfile {
+        if files::is_pattern(&logfile.file) {
+            if let Err(why) = glob::Pattern::new(&logfile.file) {
+                return Err(Error::Config(format!("Invalid file pattern {} : {}", logfile.file, why)));
+            }
+
+            // Watchers sharing a stream would invalidate the sequence token of each other
+            if !logfile.log_stream_name.contains("{file_name}") {
+                return Err(Error::Config(format!(
+                    "log_stream_name of pattern {} must contain {{file_name}}, each matching file needs its own stream",
+                    logfile.file
+                )));
+            }
+        }
+
+        for name in &[&logfile.log_group_name, &logfile.log_stream_name] {
+            if let Err(why) = config::placeholder::validate(name) {
+                return Err(Error::Config(format!("Invalid name {} for logfile {} : {}", name, logfile.file, why)));
+            }
+        }
+    }
+
+    return Ok(config);
 }
@@ -0,0 +1,441 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+// Credentials of containers: the ECS task role endpoint and the EKS
+// web identity token exchanged with STS AssumeRoleWithWebIdentity.
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use hyper;
+use hyper::{Client, Url};
+use hyper::header::{ContentType, Headers};
+use serde_json;
+use rusoto_core::default_tls_client;
+use rusoto_credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
+
+const ECS_ENDPOINT: &'static str = "http://169.254.170.2";
+// ECS agent, EKS Pod Identity agent over IPv4 and IPv6
+const CONTAINER_AGENT_ADDRESSES: [&'static str; 3] = ["169.254.170.2", "169.254.170.23", "fd00:ec2::23"];
+const RELATIVE_URI_ENV: &'static str = "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI";
+const FULL_URI_ENV: &'static str = "AWS_CONTAINER_CREDENTIALS_FULL_URI";
+const AUTHORIZATION_TOKEN_ENV: &'static str = "AWS_CONTAINER_AUTHORIZATION_TOKEN";
+const AUTHORIZATION_TOKEN_FILE_ENV: &'static str = "AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE";
+const WEB_IDENTITY_TOKEN_FILE_ENV: &'static str = "AWS_WEB_IDENTITY_TOKEN_FILE";
+const ROLE_ARN_ENV: &'static str = "AWS_ROLE_ARN";
+const ROLE_SESSION_NAME_ENV: &'static str = "AWS_ROLE_SESSION_NAME";
+const STS_ENDPOINT_ENV: &'static str = "AWS_ENDPOINT_URL_STS";
+const DEFAULT_ROLE_SESSION_NAME: &'static str = "awatchlog";
+const TIMEOUT_MILLISECONDS: u64 = 5000;
+
+/// Credentials of the ECS task role, or of any endpoint serving the same format
+///
+/// The endpoint is `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI` on the ECS agent
+/// address, or `AWS_CONTAINER_CREDENTIALS_FULL_URI`, authorized by the token of
+/// `AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE` or `AWS_CONTAINER_AUTHORIZATION_TOKEN`.
+/// A full URI must use https, unless its host is a loopback address or the
+/// address of the ECS or EKS Pod Identity agent.
+pub struct EcsProvider;
+
+impl EcsProvider {
+    /// Tell if the environment points to a container credentials endpoint
+    pub fn is_configured() -> bool {
+        return env::var(RELATIVE_URI_ENV).is_ok() || env::var(FULL_URI_ENV).is_ok();
+    }
+}
+
+// Body returned by the container credentials endpoint
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EcsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+    expiration: String,
+}
+
+impl ProvideAwsCredentials for EcsProvider {
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let url: String = if let Ok(relative_uri) = env::var(RELATIVE_URI_ENV) {
+            format!("{}{}", ECS_ENDPOINT, relative_uri)
+        } else if let Ok(full_uri) = env::var(FULL_URI_ENV) {
+            check_full_uri(&full_uri)?;
+            full_uri
+        } else {
+            return Err(CredentialsError::new("No container credentials endpoint in environment"));
+        };
+
+        return container_credentials(&url, authorization_token()?);
+    }
+}
+
+// Refuse to send the authorization token in clear text to another host
+fn check_full_uri(full_uri: &str) -> Result<(), CredentialsError> {
+    let url: Url = match Url::parse(full_uri) {
+        Ok(url) => url,
+        Err(why) => return Err(CredentialsError::new(format!("Invalid {} {} : {}", FULL_URI_ENV, full_uri, why))),
+    };
+
+    if url.scheme() == "https" {
+        return Ok(());
+    }
+
+    let host: &str = url.host_str().unwrap_or("").trim_left_matches('[').trim_right_matches(']');
+    let allowed: bool = url.scheme() == "http" && (host == "localhost" || match host.parse::<IpAddr>() {
+        Ok(address) => address.is_loopback() || CONTAINER_AGENT_ADDRESSES.iter()
+            .any(|agent| agent.parse::<IpAddr>().ok() == Some(address)),
+        Err(_) => false,
+    });
+
+    if !allowed {
+        return Err(CredentialsError::new(format!(
+            "{} {} must use https, a loopback address or the address of the ECS or EKS agent",
+            FULL_URI_ENV,
+            full_uri
+        )));
+    }
+
+    return Ok(());
+}
+
+fn container_credentials(url: &str, token: Option<String>) -> Result<AwsCredentials, CredentialsError> {
+    let mut headers = Headers::new();
+    if let Some(token) = token {
+        headers.set_raw("Authorization", vec![token.into_bytes()]);
+    }
+
+    let body: String = read_response(http_client()?.get(url).headers(headers).send(), url)?;
+    let credentials: EcsCredentials = match serde_json::from_str(&body) {
+        Ok(credentials) => credentials,
+        Err(why) => return Err(CredentialsError::new(format!("Invalid container credentials : {}", why))),
+    };
+
+    return Ok(AwsCredentials::new(
+        credentials.access_key_id,
+        credentials.secret_access_key,
+        credentials.token,
+        parse_expiration(&credentials.expiration)?,
+    ));
+}
+
+/// Credentials of the role of an EKS service account
+///
+/// The token of `AWS_WEB_IDENTITY_TOKEN_FILE` is exchanged for the role
+/// `AWS_ROLE_ARN`. The request is not signed, it needs no other credentials,
+/// and the token is sent in its body rather than in the URL which may be logged.
+/// STS is reached at `AWS_ENDPOINT_URL_STS`, or in the region of the environment.
+pub struct WebIdentityProvider;
+
+impl WebIdentityProvider {
+    /// Tell if the environment holds a web identity token and a role
+    pub fn is_configured() -> bool {
+        return env::var(WEB_IDENTITY_TOKEN_FILE_ENV).is_ok() && env::var(ROLE_ARN_ENV).is_ok();
+    }
+}
+
+impl ProvideAwsCredentials for WebIdentityProvider {
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let (token_file, role_arn) = match (env::var(WEB_IDENTITY_TOKEN_FILE_ENV), env::var(ROLE_ARN_ENV)) {
+            (Ok(token_file), Ok(role_arn)) => (token_file, role_arn),
+            _ => return Err(CredentialsError::new("No web identity token or role in environment")),
+        };
+
+        let role_session_name: String = env::var(ROLE_SESSION_NAME_ENV)
+            .unwrap_or(DEFAULT_ROLE_SESSION_NAME.to_string());
+
+        return web_identity_credentials(&sts_endpoint(), &token_file, &role_arn, &role_session_name);
+    }
+}
+
+fn web_identity_credentials(
+    endpoint: &str,
+    token_file: &str,
+    role_arn: &str,
+    session_name: &str
+) -> Result<AwsCredentials, CredentialsError> {
+    // The token is rotated on disk, read it on each refresh
+    let token: String = read_token_file(token_file)?;
+
+    let form: String = format!(
+        "Action=AssumeRoleWithWebIdentity&Version=2011-06-15&RoleArn={}&RoleSessionName={}&WebIdentityToken={}",
+        encode_form_value(role_arn),
+        encode_form_value(session_name),
+        encode_form_value(&token)
+    );
+
+    let client: Client = http_client()?;
+    let request = client.post(&format!("{}/", endpoint))
+        .header(ContentType::form_url_encoded())
+        .body(form.as_str());
+    let body: String = read_response(request.send(), "STS AssumeRoleWithWebIdentity")?;
+
+    let value = |tag: &str| -> Result<String, CredentialsError> {
+        return xml_value(&body, tag).ok_or(CredentialsError::new(format!(
+            "No {} in STS AssumeRoleWithWebIdentity response for role {}",
+            tag,
+            role_arn
+        )));
+    };
+
+    return Ok(AwsCredentials::new(
+        value("AccessKeyId")?,
+        value("SecretAccessKey")?,
+        Some(value("SessionToken")?),
+        parse_expiration(&value("Expiration")?)?,
+    ));
+}
+
+fn http_client() -> Result<Client, CredentialsError> {
+    let mut client: Client = match default_tls_client() {
+        Ok(client) => client,
+        Err(why) => return Err(CredentialsError::new(format!("Cannot create HTTP client : {}", why))),
+    };
+
+    let timeout = Duration::from_millis(TIMEOUT_MILLISECONDS);
+    client.set_read_timeout(Some(timeout));
+    client.set_write_timeout(Some(timeout));
+
+    return Ok(client);
+}
+
+fn authorization_token() -> Result<Option<String>, CredentialsError> {
+    if let Ok(token_file) = env::var(AUTHORIZATION_TOKEN_FILE_ENV) {
+        return read_token_file(&token_file).map(Some);
+    }
+
+    return Ok(env::var(AUTHORIZATION_TOKEN_ENV).ok());
+}
+
+fn read_token_file(path: &str) -> Result<String, CredentialsError> {
+    let mut token = String::new();
+    if let Err(why) = File::open(path).and_then(|mut file| file.read_to_string(&mut token)) {
+        return Err(CredentialsError::new(format!("Cannot read token file {} : {}", path, why)));
+    }
+
+    return Ok(token.trim().to_string());
+}
+
+fn sts_endpoint() -> String {
+    if let Ok(endpoint) = env::var(STS_ENDPOINT_ENV) {
+        return endpoint.trim_right_matches('/').to_string();
+    }
+
+    return match env::var("AWS_REGION").or(env::var("AWS_DEFAULT_REGION")) {
+        Ok(ref region) if region.starts_with("cn-") => format!("https://sts.{}.amazonaws.com.cn", region),
+        Ok(region) => format!("https://sts.{}.amazonaws.com", region),
+        Err(_) => "https://sts.amazonaws.com".to_string(),
+    };
+}
+
+fn read_response(result: hyper::Result<hyper::client::Response>, target: &str) -> Result<String, CredentialsError> {
+    let mut response = match result {
+        Ok(response) => response,
+        Err(why) => return Err(CredentialsError::new(format!("Cannot reach {} : {}", target, why))),
+    };
+
+    let mut body = String::new();
+    if let Err(why) = response.read_to_string(&mut body) {
+        return Err(CredentialsError::new(format!("Cannot read response of {} : {}", target, why)));
+    }
+
+    if !response.status.is_success() {
+        let message: String = xml_value(&body, "Message").unwrap_or(body.trim().to_string());
+        return Err(CredentialsError::new(format!("{} answered {} : {}", target, response.status, message)));
+    }
+
+    return Ok(body);
+}
+
+fn parse_expiration(expiration: &str) -> Result<DateTime<Utc>, CredentialsError> {
+    return match DateTime::parse_from_rfc3339(expiration) {
+        Ok(expires_at) => Ok(expires_at.with_timezone(&Utc)),
+        Err(why) => Err(CredentialsError::new(format!("Invalid expiration {} : {}", expiration, why))),
+    };
+}
+
+// Text of the first <tag> element, enough for the flat STS responses
+fn xml_value(body: &str, tag: &str) -> Option<String> {
+    let open: String = format!("<{}>", tag);
+    let close: String = format!("</{}>", tag);
+
+    let start: usize = body.find(&open)? + open.len();
+    let end: usize = start + body[start..].find(&close)?;
+
+    return Some(body[start..end].trim().to_string());
+}
+
+// Percent-encode everything but the unreserved characters of RFC 3986
+fn encode_form_value(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    return encoded;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use config::test_server::Server;
+    use super::{check_full_uri, container_credentials, web_identity_credentials};
+
+    const CREDENTIALS: &'static str = r#"{
+        "AccessKeyId": "ASIAKEY",
+        "SecretAccessKey": "secret",
+        "Token": "session",
+        "Expiration": "2030-01-01T00:00:00Z"
+    }"#;
+
+    const ASSUMED_ROLE: &'static str = r#"<AssumeRoleWithWebIdentityResponse>
+  <AssumeRoleWithWebIdentityResult>
+    <Credentials>
+      <AccessKeyId>ASIAROLE</AccessKeyId>
+      <SecretAccessKey>role-secret</SecretAccessKey>
+      <SessionToken>role-session</SessionToken>
+      <Expiration>2030-01-01T00:00:00Z</Expiration>
+    </Credentials>
+  </AssumeRoleWithWebIdentityResult>
+</AssumeRoleWithWebIdentityResponse>"#;
+
+    fn token_file(name: &str, token: &str) -> String {
+        let path: String = format!("{}/awatchlog-{}-{}", env::temp_dir().display(), name, ::std::process::id());
+        fs::write(&path, token).unwrap();
+
+        return path;
+    }
+
+    #[test]
+    fn full_uri_must_be_https_or_a_local_agent() {
+        for uri in &[
+            "https://credentials.example.com/role",
+            "http://127.0.0.1:51679/credentials",
+            "http://localhost/credentials",
+            "http://[::1]:8080/credentials",
+            "http://169.254.170.2/v2/credentials/id",
+            "http://169.254.170.23/v1/credentials",
+            "http://[fd00:ec2::23]/v1/credentials",
+        ] {
+            assert!(check_full_uri(uri).is_ok(), "{} should be allowed", uri);
+        }
+
+        for uri in &[
+            "http://credentials.example.com/role",
+            "http://10.0.0.1/credentials",
+            "http://169.254.169.254/latest/meta-data",
+            "ftp://127.0.0.1/credentials",
+            "not an uri",
+        ] {
+            assert!(check_full_uri(uri).is_err(), "{} should be refused", uri);
+        }
+    }
+
+    #[test]
+    fn credentials_are_fetched_with_the_authorization_token() {
+        let server = Server::start(|_| (200, CREDENTIALS.to_string()));
+        let url: String = format!("{}/v1/credentials", server.url);
+        assert!(check_full_uri(&url).is_ok());
+
+        let credentials = container_credentials(&url, Some("pod-token".to_string())).unwrap();
+        assert_eq!("ASIAKEY", credentials.aws_access_key_id());
+        assert_eq!("secret", credentials.aws_secret_access_key());
+        assert_eq!(&Some("session".to_string()), credentials.token());
+
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!("/v1/credentials", requests[0].path);
+        assert_eq!(Some("pod-token"), requests[0].header("Authorization"));
+    }
+
+    #[test]
+    fn error_status_is_reported() {
+        let server = Server::start(|_| (403, "denied".to_string()));
+
+        let error = container_credentials(&format!("{}/v1/credentials", server.url), None).unwrap_err();
+        assert!(error.message.contains("403"), "{}", error.message);
+        assert_eq!(None, server.requests()[0].header("Authorization"));
+    }
+
+    #[test]
+    fn web_identity_token_is_posted_in_the_body() {
+        let server = Server::start(|_| (200, ASSUMED_ROLE.to_string()));
+        let path: String = token_file("web-identity-token", "eyJ.token/+=\n");
+
+        let credentials = web_identity_credentials(
+            &server.url,
+            &path,
+            "arn:aws:iam::123456789012:role/logs",
+            "awatchlog"
+        ).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!("ASIAROLE", credentials.aws_access_key_id());
+        assert_eq!("role-secret", credentials.aws_secret_access_key());
+        assert_eq!(&Some("role-session".to_string()), credentials.token());
+
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!("POST", requests[0].method);
+        assert_eq!("/", requests[0].path);
+        assert_eq!(Some("application/x-www-form-urlencoded"), requests[0].header("Content-Type"));
+        assert_eq!(
+            "Action=AssumeRoleWithWebIdentity&Version=2011-06-15\
+             &RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Flogs\
+             &RoleSessionName=awatchlog&WebIdentityToken=eyJ.token%2F%2B%3D",
+            requests[0].body
+        );
+    }
+
+    #[test]
+    fn web_identity_error_is_reported() {
+        let server = Server::start(|_| (403, "<Error><Message>Not authorized</Message></Error>".to_string()));
+        let path: String = token_file("web-identity-denied", "eyJ.token");
+
+        let error = web_identity_credentials(&server.url, &path, "arn:aws:iam::123456789012:role/logs", "awatchlog")
+            .unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.message.contains("403") && error.message.contains("Not authorized"), "{}", error.message);
+
+        let missing = web_identity_credentials(&server.url, &path, "arn:aws:iam::123456789012:role/logs", "awatchlog")
+            .unwrap_err();
+        assert!(missing.message.contains("Cannot read token file"), "{}", missing.message);
+        assert_eq!(1, server.requests().len());
+    }
+}
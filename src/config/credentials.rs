@@ -34,41 +34,617 @@ extern crate toml;
 extern crate rusoto_credential;
 
 use config;
+use config::container::{EcsProvider, WebIdentityProvider};
+use config::encryption;
+use error::Error;
+use config::profile;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
-use rusoto_credential::{StaticProvider};
+use std::process::Command;
+use std::str::FromStr;
+use chrono::{DateTime, Duration, Utc};
+use serde_json;
+use libc;
+use hyper::Client;
+use rusoto_core::{default_tls_client, Region};
+use rusoto_sts::{AssumeRoleRequest, Sts, StsClient};
+use rusoto_credential::{
+    AwsCredentials,
+    CredentialsError,
+    EnvironmentProvider,
+    InstanceMetadataProvider,
+    ProvideAwsCredentials,
+    StaticProvider,
+};
 
 const DEFAULT_CREDENTIALS_PATH: &'static str = "/etc/awatchlog/credentials.toml";
 
+// Keys read from a file are read again after this delay to pick up changes
+const FILE_CREDENTIALS_LIFETIME_SECS: i64 = 600;
+
+// credential_process output without expiration is kept that long
+const NON_EXPIRING_LIFETIME_DAYS: i64 = 365;
+
+// Assumed role credentials are renewed this long before they expire
+const ASSUME_ROLE_REFRESH_MARGIN_SECS: i64 = 300;
+
+// Session name of the roles of the shared profiles without role_session_name
+const DEFAULT_PROFILE_ROLE_SESSION_NAME: &'static str = "awatchlog";
+
+// Profile settings the agent cannot get credentials from, besides the sso_ ones
+const UNSUPPORTED_PROFILE_KEYS: [&'static str; 3] = ["credential_source", "web_identity_token_file", "mfa_serial"];
+
 #[derive(Deserialize)]
 struct AwatchLogCredentials {
-    aws_access_key_id: String,
-    aws_secret_access_key: String,
+    aws_access_key_id: Option<String>,
+    aws_secret_access_key: Option<String>,
+    /// Token of temporary credentials
+    aws_session_token: Option<String>,
+    /// Profile of `~/.aws/credentials` and `~/.aws/config` to use instead of keys
+    profile: Option<String>,
+    /// Command printing the credentials, as in the AWS config file
+    credential_process: Option<String>,
 }
 
-// Parse credentials file
-pub fn parse(file: Option<String>) -> Option<StaticProvider> {
-    let credentials_content: String;
+/// Source of the AWS credentials used by the clients
+pub enum Credentials {
+    /// Keys written in the credentials file
+    Static(StaticProvider),
+    /// Named profile of the shared AWS files
+    Profile(SharedProfileProvider),
+    /// External command printing the credentials
+    Process(ProcessProvider),
+    /// Environment, profile, container then instance metadata
+    Chain(ChainProvider),
+    /// Role assumed with other credentials
+    AssumeRole(Box<AssumeRoleProvider>),
+}
+
+impl ProvideAwsCredentials for Credentials {
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        return match *self {
+            Credentials::Static(ref provider) => provider.credentials(),
+            Credentials::Profile(ref provider) => provider.credentials(),
+            Credentials::Process(ref provider) => provider.credentials(),
+            Credentials::Chain(ref provider) => provider.credentials(),
+            Credentials::AssumeRole(ref provider) => provider.credentials(),
+        };
+    }
+}
+
+/// Read the credentials of a profile of `~/.aws/credentials` and `~/.aws/config`
+///
+/// Settings of the credentials file take precedence over the config file ones.
+/// The profile gives either static keys, a `credential_process`, or a `role_arn`
+/// assumed with the credentials of its `source_profile`. SSO, `credential_source`,
+/// web identity and MFA profiles are refused with the keys not supported.
+pub struct SharedProfileProvider {
+    pub profile: String,
+}
+
+impl ProvideAwsCredentials for SharedProfileProvider {
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        return profile_credentials(&self.profile, &mut vec![]);
+    }
+}
+
+// Credentials of a profile, `sources` holds the profiles whose role
+// is assumed with them, so a loop of source_profile is detected.
+fn profile_credentials(name: &str, sources: &mut Vec<String>) -> Result<AwsCredentials, CredentialsError> {
+    if sources.iter().any(|source| source == name) {
+        return Err(CredentialsError::new(format!(
+            "Profile {} is its own source through {}",
+            name,
+            sources.join(" -> ")
+        )));
+    }
+
+    let config_settings = profile::config_section(name);
+    let credentials_settings = profile::credentials_section(name);
+
+    if config_settings.is_none() && credentials_settings.is_none() {
+        return Err(CredentialsError::new(format!("Profile {} not found", name)));
+    }
+
+    let mut settings: HashMap<String, String> = config_settings.unwrap_or_default();
+    settings.extend(credentials_settings.unwrap_or_default());
+
+    let unsupported: Vec<String> = unsupported_profile_keys(&settings);
+    if !unsupported.is_empty() {
+        return Err(CredentialsError::new(format!(
+            "Profile {} uses {}, not supported, use keys, credential_process or role_arn with source_profile",
+            name,
+            unsupported.join(", ")
+        )));
+    }
+
+    if let Some(role_arn) = settings.get("role_arn") {
+        let source_credentials: AwsCredentials = match settings.get("source_profile") {
+            // The keys next to the role are the ones to assume it with
+            Some(source) if source == name => static_profile_credentials(name, &settings)?,
+            Some(source) => {
+                sources.push(name.to_string());
+                profile_credentials(source, sources)?
+            },
+            None => return Err(CredentialsError::new(format!("Profile {} has a role_arn but no source_profile", name))),
+        };
+
+        return assume_profile_role(name, role_arn, &settings, source_credentials);
+    }
+
+    if settings.contains_key("aws_access_key_id") {
+        return static_profile_credentials(name, &settings);
+    }
+
+    if let Some(command) = settings.get("credential_process") {
+        return process_credentials(command);
+    }
+
+    return Err(CredentialsError::new(format!("No credentials in profile {}", name)));
+}
+
+// Keys of the profile which would need a login, a prompt or another provider
+fn unsupported_profile_keys(settings: &HashMap<String, String>) -> Vec<String> {
+    let mut keys: Vec<String> = settings.keys()
+        .filter(|key| key.starts_with("sso_") || UNSUPPORTED_PROFILE_KEYS.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    keys.sort();
+
+    return keys;
+}
+
+fn static_profile_credentials(name: &str, settings: &HashMap<String, String>) -> Result<AwsCredentials, CredentialsError> {
+    return match (settings.get("aws_access_key_id"), settings.get("aws_secret_access_key")) {
+        (Some(key), Some(secret)) => Ok(AwsCredentials::new(
+            key.to_owned(),
+            secret.to_owned(),
+            settings.get("aws_session_token").cloned(),
+            Utc::now() + Duration::seconds(FILE_CREDENTIALS_LIFETIME_SECS),
+        )),
+        _ => Err(CredentialsError::new(format!("No keys in profile {}", name))),
+    };
+}
+
+// Assume the role of a profile, STS is reached in the region of the
+// profile, of the environment, or in us-east-1
+fn assume_profile_role(
+    name: &str,
+    role_arn: &str,
+    settings: &HashMap<String, String>,
+    source_credentials: AwsCredentials
+) -> Result<AwsCredentials, CredentialsError> {
+    let region_name: Option<String> = settings.get("region").cloned()
+        .or(env::var("AWS_REGION").ok())
+        .or(env::var("AWS_DEFAULT_REGION").ok());
+    let region: Region = match region_name {
+        Some(region_name) => match Region::from_str(&region_name) {
+            Ok(region) => region,
+            Err(_) => return Err(CredentialsError::new(format!("Invalid region {} for the role of profile {}", region_name, name))),
+        },
+        None => Region::UsEast1,
+    };
 
-    match file {
+    let role = AssumeRole {
+        role_arn: role_arn.to_string(),
+        external_id: settings.get("external_id").cloned(),
+        role_session_name: settings.get("role_session_name").cloned()
+            .unwrap_or(DEFAULT_PROFILE_ROLE_SESSION_NAME.to_string()),
+        region,
+    };
+
+    let source = Credentials::Static(StaticProvider::new(
+        source_credentials.aws_access_key_id().to_owned(),
+        source_credentials.aws_secret_access_key().to_owned(),
+        source_credentials.token().to_owned(),
+        None,
+    ));
+
+    return match AssumeRoleProvider::new(source, role) {
+        Ok(provider) => provider.credentials(),
+        Err(why) => Err(CredentialsError::new(format!("{}", why))),
+    };
+}
+
+/// Run a command printing the credentials as the AWS `credential_process` does
+pub struct ProcessProvider {
+    pub command: String,
+}
+
+impl ProvideAwsCredentials for ProcessProvider {
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        return process_credentials(&self.command);
+    }
+}
+
+/// Look for credentials in the environment, the profile selected by
+/// `AWS_PROFILE`, the EKS web identity, the ECS container endpoint and
+/// the instance metadata
+///
+/// When the environment configures a web identity or a container endpoint,
+/// its error is returned instead of falling back to the instance role.
+pub struct ChainProvider {
+    profile: SharedProfileProvider,
+}
+
+impl ChainProvider {
+    pub fn new() -> ChainProvider {
+        return ChainProvider {
+            profile: SharedProfileProvider { profile: profile::profile_name() },
+        };
+    }
+}
+
+impl ProvideAwsCredentials for ChainProvider {
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        if let Ok(credentials) = EnvironmentProvider.credentials() {
+            return Ok(credentials);
+        }
+
+        if let Ok(credentials) = self.profile.credentials() {
+            return Ok(credentials);
+        }
+
+        if WebIdentityProvider::is_configured() {
+            return WebIdentityProvider.credentials();
+        }
+
+        if EcsProvider::is_configured() {
+            return EcsProvider.credentials();
+        }
+
+        if let Ok(credentials) = InstanceMetadataProvider.credentials() {
+            return Ok(credentials);
+        }
+
+        return Err(CredentialsError::new(format!(
+            "No credentials found in environment, profile {}, web identity, container or instance metadata",
+            self.profile.profile
+        )));
+    }
+}
+
+/// Role to assume with STS AssumeRole
+#[derive(Clone, Debug)]
+pub struct AssumeRole {
+    pub role_arn: String,
+    pub external_id: Option<String>,
+    pub role_session_name: String,
+    /// Region of the STS endpoint
+    pub region: Region,
+}
+
+/// Assume a role using other credentials
+///
+/// The credentials are reported as expiring a few minutes early, so the
+/// auto refreshing provider renews them before they are refused.
+pub struct AssumeRoleProvider {
+    role: AssumeRole,
+    sts: StsClient<Credentials, Client>,
+}
+
+impl AssumeRoleProvider {
+    pub fn new(credentials: Credentials, role: AssumeRole) -> Result<AssumeRoleProvider, Error> {
+        let dispatcher: Client = match default_tls_client() {
+            Ok(dispatcher) => dispatcher,
+            Err(why) => return Err(Error::Aws(format!("Cannot create STS client : {}", why))),
+        };
+        let region: Region = role.region.clone();
+
+        return Ok(AssumeRoleProvider {
+            role,
+            sts: StsClient::new(dispatcher, credentials, region),
+        });
+    }
+}
+
+impl ProvideAwsCredentials for AssumeRoleProvider {
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let request = AssumeRoleRequest {
+            role_arn: self.role.role_arn.to_owned(),
+            role_session_name: self.role.role_session_name.to_owned(),
+            external_id: self.role.external_id.to_owned(),
+            ..Default::default()
+        };
+
+        let response = match self.sts.assume_role(&request) {
+            Ok(response) => response,
+            Err(why) => return Err(CredentialsError::new(format!("Cannot assume role {} : {}", self.role.role_arn, why))),
+        };
+
+        let credentials = match response.credentials {
+            Some(credentials) => credentials,
+            None => return Err(CredentialsError::new(format!("No credentials returned for role {}", self.role.role_arn))),
+        };
+
+        let expires_at: DateTime<Utc> = match DateTime::parse_from_rfc3339(&credentials.expiration) {
+            Ok(expires_at) => expires_at.with_timezone(&Utc),
+            Err(why) => return Err(CredentialsError::new(format!("Invalid expiration {} : {}", credentials.expiration, why))),
+        };
+
+        println!("Assumed role {} until {}", self.role.role_arn, expires_at);
+
+        return Ok(AwsCredentials::new(
+            credentials.access_key_id,
+            credentials.secret_access_key,
+            Some(credentials.session_token),
+            expires_at - Duration::seconds(ASSUME_ROLE_REFRESH_MARGIN_SECS),
+        ));
+    }
+}
+
+// Output of a credential_process command
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ProcessOutput {
+    version: u64,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    expiration: Option<String>,
+}
+
+fn process_credentials(command: &str) -> Result<AwsCredentials, CredentialsError> {
+    let output = match Command::new("sh").arg("-c").arg(command).output() {
+        Ok(output) => output,
+        Err(why) => return Err(CredentialsError::new(format!("Cannot run credential_process {} : {}", command, why))),
+    };
+
+    if !output.status.success() {
+        return Err(CredentialsError::new(format!(
+            "credential_process {} failed with {} : {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let process_output: ProcessOutput = match serde_json::from_slice(&output.stdout) {
+        Ok(process_output) => process_output,
+        Err(why) => return Err(CredentialsError::new(format!("Invalid credential_process output : {}", why))),
+    };
+
+    if 1 != process_output.version {
+        return Err(CredentialsError::new(format!(
+            "Unsupported credential_process output version {}",
+            process_output.version
+        )));
+    }
+
+    let expires_at: DateTime<Utc> = match process_output.expiration {
+        Some(expiration) => match DateTime::parse_from_rfc3339(&expiration) {
+            Ok(expires_at) => expires_at.with_timezone(&Utc),
+            Err(why) => return Err(CredentialsError::new(format!("Invalid credential_process expiration {} : {}", expiration, why))),
+        },
+        None => Utc::now() + Duration::days(NON_EXPIRING_LIFETIME_DAYS),
+    };
+
+    return Ok(AwsCredentials::new(
+        process_output.access_key_id,
+        process_output.secret_access_key,
+        process_output.session_token,
+        expires_at,
+    ));
+}
+
+/// Check that only the agent user can access the credentials and key files
+///
+/// Like ssh does with private keys, a file accessible by the group or by
+/// others, or owned by another user, makes the agent refuse to start when
+/// `strict` is set. Otherwise a warning is printed. The key file is the one
+/// named by `AWATCHLOG_CREDENTIALS_KEY_FILE`, if any.
+pub fn check_permissions(file: Option<&String>, strict: bool) -> Result<(), Error> {
+    let path: String = file.cloned().unwrap_or(DEFAULT_CREDENTIALS_PATH.to_string());
+    check_file_permissions("Credentials file", &path, strict)?;
+
+    return encryption::check_key_file(strict);
+}
+
+/// Check that only the agent user can access the file, see `check_permissions`
+pub fn check_file_permissions(kind: &str, path: &str, strict: bool) -> Result<(), Error> {
+    // A missing file is reported when it is read, or means the chain is used
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()),
+    };
+
+    let mut problems: Vec<String> = vec![];
+
+    let mode: u32 = metadata.mode() & 0o777;
+    if 0 != mode & 0o077 {
+        problems.push(format!("has mode {:o} which gives access to group or others", mode));
+    }
+
+    let uid: u32 = unsafe { libc::geteuid() };
+    if metadata.uid() != uid {
+        problems.push(format!("is owned by uid {} instead of uid {}", metadata.uid(), uid));
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let message: String = format!("{} {} {}", kind, path, problems.join(" and "));
+    if strict {
+        return Err(Error::Credentials(format!(
+            "{}, restrict it with chmod 600 or set strict_permissions = false",
+            message
+        )));
+    }
+
+    println!("WARNING: {}, restrict it with chmod 600, set strict_permissions = true to refuse to start", message);
+
+    return Ok(());
+}
+
+/// Encrypt a plaintext credentials file into another one, readable by the owner only
+///
+/// The passphrase is taken from the same variables used to decrypt it.
+pub fn encrypt_file(input: &str, output: &str) -> Result<(), Error> {
+    let content: String = config::parser::get_file_content(input.to_string())?;
+    if encryption::is_encrypted(&content) {
+        return Err(Error::Credentials(format!("Credentials file {} is already encrypted", input)));
+    }
+
+    if let Err(why) = toml::from_str::<AwatchLogCredentials>(&content) {
+        return Err(Error::Credentials(format!("Invalid credentials file {} : {}", input, why)));
+    }
+
+    encryption::check_key_file(false)?;
+    let encrypted: String = encryption::passphrase()
+        .and_then(|passphrase| encryption::encrypt(&content, &passphrase))
+        .map_err(Error::Credentials)?;
+
+    let mut file: File = match OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(output) {
+        Ok(file) => file,
+        Err(why) => return Err(Error::Io(format!("Cannot create {}", output), why)),
+    };
+
+    // The mode only applies to a new file, an existing one keeps its own
+    if let Err(why) = file.set_permissions(fs::Permissions::from_mode(0o600)) {
+        return Err(Error::Io(format!("Cannot restrict permissions of {}", output), why));
+    }
+
+    return file.write_all(encrypted.as_bytes())
+        .map_err(|why| Error::Io(format!("Cannot write {}", output), why));
+}
+
+// Decrypt the content of an encrypted credentials file, other content is returned as is
+fn decrypt_content(content: String) -> Result<String, Error> {
+    if !encryption::is_encrypted(&content) {
+        return Ok(content);
+    }
+
+    return encryption::passphrase()
+        .and_then(|passphrase| encryption::decrypt(&content, &passphrase))
+        .map_err(|why| Error::Credentials(format!("Cannot read encrypted credentials : {}", why)));
+}
+
+// Parse credentials file
+//
+// Without credentials file, the credentials are looked up by the ChainProvider.
+// An encrypted file is decrypted in memory.
+pub fn parse(file: Option<String>) -> Result<Credentials, Error> {
+    let path: String = match file {
         None => {
             let default_path = Path::new(&DEFAULT_CREDENTIALS_PATH);
             if default_path.exists() {
-                credentials_content = config::parser::get_file_content(DEFAULT_CREDENTIALS_PATH.to_owned());
+                DEFAULT_CREDENTIALS_PATH.to_owned()
             } else {
-                return None;
+                return Ok(Credentials::Chain(ChainProvider::new()));
             }
         },
-        Some(file_path) => {
-            credentials_content = config::parser::get_file_content(file_path);
-        },
+        Some(file_path) => file_path,
+    };
+
+    let credentials_content: String = decrypt_content(config::parser::get_file_content(path.to_owned())?)?;
+    let credentials: AwatchLogCredentials = match toml::from_str(&credentials_content) {
+        Ok(credentials) => credentials,
+        Err(why) => return Err(Error::Credentials(format!("Invalid credentials file {} : {}", path, why))),
+    };
+
+    match (credentials.aws_access_key_id, credentials.aws_secret_access_key) {
+        (Some(key), Some(secret)) => return Ok(Credentials::Static(StaticProvider::new(
+            key,
+            secret,
+            credentials.aws_session_token,
+            None,
+        ))),
+        (None, None) => {},
+        _ => return Err(Error::Credentials(format!(
+            "Credentials file {} needs both aws_access_key_id and aws_secret_access_key",
+            path
+        ))),
     }
 
-    let credentials: AwatchLogCredentials = toml::from_str(&credentials_content).unwrap();
+    if let Some(profile) = credentials.profile {
+        return Ok(Credentials::Profile(SharedProfileProvider { profile }));
+    }
 
-    return Some(StaticProvider::new(
-        credentials.aws_access_key_id,
-        credentials.aws_secret_access_key,
-        None,
-        None,
-    ));
+    if let Some(command) = credentials.credential_process {
+        return Ok(Credentials::Process(ProcessProvider { command }));
+    }
+
+    return Err(Error::Credentials(format!(
+        "Credentials file {} needs keys, a profile or a credential_process",
+        path
+    )));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use config::encryption;
+    use config::profile::read_section;
+    use super::{check_file_permissions, encrypt_file, unsupported_profile_keys};
+
+    fn file_with_mode(name: &str, mode: u32) -> String {
+        let path: String = format!("{}/awatchlog-{}-{}", env::temp_dir().display(), name, ::std::process::id());
+        File::create(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+
+        return path;
+    }
+
+    #[test]
+    fn private_file_is_accepted() {
+        let path: String = file_with_mode("private", 0o600);
+        assert!(check_file_permissions("Credentials file", &path, true).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn readable_file_is_refused_only_when_strict() {
+        let path: String = file_with_mode("readable", 0o644);
+
+        let error = check_file_permissions("Credentials key file", &path, true).unwrap_err();
+        assert!(format!("{}", error).contains("has mode 644"), "{}", error);
+        assert!(check_file_permissions("Credentials key file", &path, false).is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_left_to_the_reader() {
+        assert!(check_file_permissions("Credentials file", "/nonexistent/awatchlog/credentials.toml", true).is_ok());
+    }
+
+    #[test]
+    fn encrypted_file_is_restricted_even_if_it_existed() {
+        let input: String = file_with_mode("plain-credentials", 0o600);
+        fs::write(&input, "aws_access_key_id = \"AKIA\"\naws_secret_access_key = \"secret\"\n").unwrap();
+        let output: String = file_with_mode("encrypted-credentials", 0o644);
+        env::set_var("AWATCHLOG_CREDENTIALS_KEY", "passphrase");
+
+        encrypt_file(&input, &output).unwrap();
+
+        assert_eq!(0o600, fs::metadata(&output).unwrap().permissions().mode() & 0o777);
+        let content: String = fs::read_to_string(&output).unwrap();
+        assert!(encryption::decrypt(&content, "passphrase").unwrap().contains("AKIA"));
+
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn sso_profile_keys_are_not_supported() {
+        let config: PathBuf = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/profile/config"));
+
+        assert_eq!(
+            vec!["sso_account_id", "sso_role_name", "sso_session"],
+            unsupported_profile_keys(&read_section(&config, "profile sso").unwrap())
+        );
+        assert!(unsupported_profile_keys(&read_section(&config, "profile logs").unwrap()).is_empty());
+    }
 }
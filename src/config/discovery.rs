@@ -30,11 +30,275 @@
 // OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-
 // This module will use try to discover AWS information using metadata.
 // If the program run under AWS environment, this will allow to gather useful information.
 
-// Get current region, instanceID, hostname
-pub fn metadata()
-{
-}
\ No newline at end of file
+use std::env;
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use hyper;
+use hyper::Client;
+use hyper::header::Headers;
+use hyper::net::{HttpStream, NetworkConnector};
+use serde_json;
+
+const DEFAULT_METADATA_ENDPOINT: &'static str = "http://169.254.169.254";
+const METADATA_ENDPOINT_ENV: &'static str = "AWS_EC2_METADATA_SERVICE_ENDPOINT";
+const METADATA_DISABLED_ENV: &'static str = "AWS_EC2_METADATA_DISABLED";
+const TOKEN_TTL_HEADER: &'static str = "X-aws-ec2-metadata-token-ttl-seconds";
+const TOKEN_HEADER: &'static str = "X-aws-ec2-metadata-token";
+const TOKEN_TTL_SECONDS: &'static str = "21600";
+const TIMEOUT_MILLISECONDS: u64 = 1000;
+const HOSTNAME_PATH: &'static str = "/proc/sys/kernel/hostname";
+
+/// Information about the EC2 instance the agent runs on
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    pub instance_id: Option<String>,
+    pub region: Option<String>,
+    pub availability_zone: Option<String>,
+    pub hostname: Option<String>,
+    pub private_ip: Option<String>,
+    pub account_id: Option<String>,
+}
+
+// Connector of the HTTP client with a connect timeout,
+// outside of AWS the metadata address may not answer at all.
+struct TimeoutConnector {
+    timeout: Duration,
+}
+
+impl NetworkConnector for TimeoutConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<HttpStream> {
+        if "http" != scheme {
+            return Err(hyper::Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported scheme {} for instance metadata", scheme)
+            )));
+        }
+
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("Cannot resolve {}", host));
+        for address in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => return Ok(HttpStream(stream)),
+                Err(why) => last_error = why,
+            }
+        }
+
+        return Err(hyper::Error::Io(last_error));
+    }
+}
+
+/// Get current region, instanceID, hostname, private IP and account ID
+///
+/// The endpoint defaults to `AWS_EC2_METADATA_SERVICE_ENDPOINT` then to
+/// the link local address of the service. IMDSv2 is used when a session
+/// token can be obtained, IMDSv1 otherwise. Return None when the service
+/// does not answer, e.g. outside of EC2.
+pub fn metadata(endpoint: Option<String>) -> Option<Metadata> {
+    if let Ok(disabled) = env::var(METADATA_DISABLED_ENV) {
+        if "true" == disabled.to_lowercase() {
+            return None;
+        }
+    }
+
+    let endpoint: String = endpoint
+        .or(env::var(METADATA_ENDPOINT_ENV).ok())
+        .unwrap_or(DEFAULT_METADATA_ENDPOINT.to_string());
+
+    return fetch(endpoint.trim_right_matches('/'));
+}
+
+// Query the metadata service at the given base URL
+fn fetch(endpoint: &str) -> Option<Metadata> {
+    let timeout = Duration::from_millis(TIMEOUT_MILLISECONDS);
+    let mut client = Client::with_connector(TimeoutConnector { timeout });
+    client.set_read_timeout(Some(timeout));
+    client.set_write_timeout(Some(timeout));
+
+    let token: Option<String> = get_token(&client, endpoint);
+
+    let instance_id: Option<String> = get(&client, endpoint, "meta-data/instance-id", &token);
+    if instance_id.is_none() {
+        return None;
+    }
+
+    let document: Option<serde_json::Value> = get(&client, endpoint, "dynamic/instance-identity/document", &token)
+        .and_then(|body| serde_json::from_str(&body).ok());
+    let document_field = |name: &str| -> Option<String> {
+        document.as_ref()
+            .and_then(|document| document.get(name))
+            .and_then(|value| value.as_str())
+            .map(|value| value.to_string())
+    };
+
+    let availability_zone: Option<String> = get(&client, endpoint, "meta-data/placement/availability-zone", &token)
+        .or(document_field("availabilityZone"));
+
+    // Older metadata versions have no region entry, it is the zone minus its letter
+    let region: Option<String> = get(&client, endpoint, "meta-data/placement/region", &token)
+        .or(document_field("region"))
+        .or(availability_zone.as_ref().map(|zone| {
+            zone.trim_right_matches(|c: char| c.is_alphabetic()).to_string()
+        }));
+
+    return Some(Metadata {
+        instance_id,
+        region,
+        availability_zone,
+        hostname: get(&client, endpoint, "meta-data/local-hostname", &token),
+        private_ip: get(&client, endpoint, "meta-data/local-ipv4", &token)
+            .or(document_field("privateIp")),
+        account_id: document_field("accountId"),
+    });
+}
+
+/// Name of the host, as given by the kernel
+pub fn hostname() -> Option<String> {
+    if let Ok(hostname) = env::var("HOSTNAME") {
+        if !hostname.is_empty() {
+            return Some(hostname);
+        }
+    }
+
+    let mut hostname = String::new();
+    return match File::open(HOSTNAME_PATH).and_then(|mut file| file.read_to_string(&mut hostname)) {
+        Ok(_) if !hostname.trim().is_empty() => Some(hostname.trim().to_string()),
+        _ => None,
+    };
+}
+
+/// IP address of the interface used to reach the outside
+///
+/// Connecting an UDP socket sends nothing, it only selects the route.
+pub fn ip_address() -> Option<String> {
+    let socket: UdpSocket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(_) => return None,
+    };
+
+    if socket.connect("8.8.8.8:80").is_err() {
+        return None;
+    }
+
+    return socket.local_addr().ok().map(|address| address.ip().to_string());
+}
+
+// Request an IMDSv2 session token
+fn get_token(client: &Client, endpoint: &str) -> Option<String> {
+    let mut headers = Headers::new();
+    headers.set_raw(TOKEN_TTL_HEADER, vec![TOKEN_TTL_SECONDS.as_bytes().to_vec()]);
+
+    let url: String = format!("{}/latest/api/token", endpoint);
+    return read_response(client.put(&url).headers(headers).send());
+}
+
+fn get(client: &Client, endpoint: &str, path: &str, token: &Option<String>) -> Option<String> {
+    let mut headers = Headers::new();
+    if let Some(ref token) = *token {
+        headers.set_raw(TOKEN_HEADER, vec![token.as_bytes().to_vec()]);
+    }
+
+    let url: String = format!("{}/latest/{}", endpoint, path);
+    return read_response(client.get(&url).headers(headers).send());
+}
+
+fn read_response(result: hyper::Result<hyper::client::Response>) -> Option<String> {
+    let mut response = match result {
+        Ok(response) => response,
+        Err(_) => return None,
+    };
+
+    if !response.status.is_success() {
+        return None;
+    }
+
+    let mut body = String::new();
+    if response.read_to_string(&mut body).is_err() {
+        return None;
+    }
+
+    let body: &str = body.trim();
+    if body.is_empty() {
+        return None;
+    }
+
+    return Some(body.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::test_server::{Request, Server};
+
+    const TOKEN: &'static str = "AQAEAtoken==";
+
+    // Answer like the metadata service, IMDSv2 only when `session` is set
+    fn answer(request: &Request, session: bool) -> (u16, String) {
+        if "PUT" == request.method && "/latest/api/token" == request.path {
+            return match (session, request.header(TOKEN_TTL_HEADER)) {
+                (true, Some(TOKEN_TTL_SECONDS)) => (200, TOKEN.to_string()),
+                (true, _) => (400, String::new()),
+                (false, _) => (403, String::new()),
+            };
+        }
+
+        if session && Some(TOKEN) != request.header(TOKEN_HEADER) {
+            return (401, String::new());
+        }
+
+        return match request.path.as_str() {
+            "/latest/meta-data/instance-id" => (200, "i-0123456789abcdef0".to_string()),
+            "/latest/meta-data/placement/availability-zone" => (200, "eu-west-3b".to_string()),
+            "/latest/meta-data/local-hostname" => (200, "ip-10-0-0-12.eu-west-3.compute.internal".to_string()),
+            "/latest/meta-data/local-ipv4" => (200, "10.0.0.12".to_string()),
+            "/latest/dynamic/instance-identity/document" => (200, "{\"accountId\": \"123456789012\"}".to_string()),
+            _ => (404, String::new()),
+        };
+    }
+
+    #[test]
+    fn session_token_is_sent_with_each_request() {
+        let server = Server::start(|request| answer(request, true));
+        let metadata = fetch(&server.url).unwrap();
+
+        assert_eq!(Some("i-0123456789abcdef0".to_string()), metadata.instance_id);
+        assert_eq!(Some("123456789012".to_string()), metadata.account_id);
+        assert_eq!(Some("10.0.0.12".to_string()), metadata.private_ip);
+
+        let requests = server.requests();
+        assert_eq!("PUT", requests[0].method);
+        assert!(requests[1..].iter().all(|request| "GET" == request.method && Some(TOKEN) == request.header(TOKEN_HEADER)));
+    }
+
+    #[test]
+    fn falls_back_to_imdsv1_without_token() {
+        let server = Server::start(|request| answer(request, false));
+        let metadata = fetch(&server.url).unwrap();
+
+        assert_eq!(Some("i-0123456789abcdef0".to_string()), metadata.instance_id);
+        assert_eq!(Some("ip-10-0-0-12.eu-west-3.compute.internal".to_string()), metadata.hostname);
+        assert!(server.requests()[1..].iter().all(|request| request.header(TOKEN_HEADER).is_none()));
+    }
+
+    #[test]
+    fn region_comes_from_availability_zone() {
+        let server = Server::start(|request| answer(request, true));
+        let metadata = fetch(&server.url).unwrap();
+
+        assert_eq!(Some("eu-west-3b".to_string()), metadata.availability_zone);
+        assert_eq!(Some("eu-west-3".to_string()), metadata.region);
+    }
+
+    #[test]
+    fn none_without_instance_id() {
+        let server = Server::start(|_| (404, String::new()));
+
+        assert!(fetch(&server.url).is_none());
+    }
+}
@@ -0,0 +1,243 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+// Encryption at rest of the credentials file.
+//
+// The key is derived from a passphrase with PBKDF2-HMAC-SHA256 and the
+// content sealed with ChaCha20-Poly1305. The file is a header line followed
+// by the base64 of: salt (16) | nonce (12) | iterations (4, big endian) | ciphertext and tag.
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use base64;
+use ring::{aead, digest, pbkdf2};
+use ring::rand::{SecureRandom, SystemRandom};
+use config::credentials;
+use error::Error;
+
+const HEADER: &'static str = "awatchlog-encrypted-credentials v1";
+const KEY_ENV: &'static str = "AWATCHLOG_CREDENTIALS_KEY";
+const KEY_FILE_ENV: &'static str = "AWATCHLOG_CREDENTIALS_KEY_FILE";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const ITERATIONS_LEN: usize = 4;
+const KEY_LEN: usize = 32;
+const ITERATIONS: u32 = 100000;
+
+static ALGORITHM: &'static aead::Algorithm = &aead::CHACHA20_POLY1305;
+
+/// Tell if the content is an encrypted credentials file
+pub fn is_encrypted(content: &str) -> bool {
+    return content.trim_left().starts_with(HEADER);
+}
+
+// File holding the passphrase, named by `AWATCHLOG_CREDENTIALS_KEY_FILE`
+fn key_file() -> Option<String> {
+    return env::var(KEY_FILE_ENV).ok();
+}
+
+/// Check the permissions of the key file as the credentials file ones
+///
+/// The passphrase unlocks the credentials file, it must not be readable by
+/// more users than the credentials themselves. Nothing to check without a key file.
+pub fn check_key_file(strict: bool) -> Result<(), Error> {
+    return match key_file() {
+        Some(path) => credentials::check_file_permissions("Credentials key file", &path, strict),
+        None => Ok(()),
+    };
+}
+
+/// Passphrase of the credentials file
+///
+/// Read from the file named by `AWATCHLOG_CREDENTIALS_KEY_FILE`, or from
+/// `AWATCHLOG_CREDENTIALS_KEY`.
+pub fn passphrase() -> Result<String, String> {
+    if let Some(path) = key_file() {
+        let mut passphrase = String::new();
+        if let Err(why) = File::open(&path).and_then(|mut file| file.read_to_string(&mut passphrase)) {
+            return Err(format!("Cannot read key file {} : {}", path, why));
+        }
+
+        return non_empty(passphrase.trim_right_matches(|c| c == '\n' || c == '\r').to_string());
+    }
+
+    return match env::var(KEY_ENV) {
+        Ok(passphrase) => non_empty(passphrase),
+        Err(_) => Err(format!("Credentials are encrypted, set {} or {}", KEY_FILE_ENV, KEY_ENV)),
+    };
+}
+
+fn non_empty(passphrase: String) -> Result<String, String> {
+    if passphrase.is_empty() {
+        return Err("Empty credentials passphrase".to_string());
+    }
+
+    return Ok(passphrase);
+}
+
+/// Encrypt the content with the passphrase
+pub fn encrypt(content: &str, passphrase: &str) -> Result<String, String> {
+    let random = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    if random.fill(&mut salt).is_err() || random.fill(&mut nonce).is_err() {
+        return Err("Cannot generate random salt and nonce".to_string());
+    }
+
+    let key = aead::SealingKey::new(ALGORITHM, &derive_key(passphrase, &salt, ITERATIONS))
+        .map_err(|_| "Cannot create encryption key".to_string())?;
+
+    let mut in_out: Vec<u8> = content.as_bytes().to_vec();
+    in_out.extend(vec![0u8; ALGORITHM.tag_len()]);
+
+    let sealed_len: usize = aead::seal_in_place(&key, &nonce, HEADER.as_bytes(), &mut in_out, ALGORITHM.tag_len())
+        .map_err(|_| "Cannot encrypt credentials".to_string())?;
+    in_out.truncate(sealed_len);
+
+    let mut blob: Vec<u8> = vec![];
+    blob.extend_from_slice(&salt);
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&[
+        (ITERATIONS >> 24) as u8,
+        (ITERATIONS >> 16) as u8,
+        (ITERATIONS >> 8) as u8,
+        ITERATIONS as u8,
+    ]);
+    blob.extend(in_out);
+
+    return Ok(format!("{}\n{}\n", HEADER, base64::encode(&blob)));
+}
+
+/// Decrypt the content of an encrypted credentials file
+///
+/// A wrong passphrase and a modified file are both reported as a failure
+/// to authenticate the content.
+pub fn decrypt(content: &str, passphrase: &str) -> Result<String, String> {
+    if !is_encrypted(content) {
+        return Err("Missing encrypted credentials header".to_string());
+    }
+
+    let encoded: String = content.trim_left()[HEADER.len()..]
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let mut blob: Vec<u8> = base64::decode(&encoded)
+        .map_err(|why| format!("Invalid encrypted credentials : {}", why))?;
+
+    let prefix_len: usize = SALT_LEN + NONCE_LEN + ITERATIONS_LEN;
+    if blob.len() < prefix_len + ALGORITHM.tag_len() {
+        return Err("Encrypted credentials are truncated".to_string());
+    }
+
+    let salt: Vec<u8> = blob[..SALT_LEN].to_vec();
+    let nonce: Vec<u8> = blob[SALT_LEN..SALT_LEN + NONCE_LEN].to_vec();
+    let iterations: u32 = blob[SALT_LEN + NONCE_LEN..prefix_len]
+        .iter()
+        .fold(0u32, |iterations, byte| (iterations << 8) | *byte as u32);
+
+    if 0 == iterations {
+        return Err("Invalid key derivation iterations".to_string());
+    }
+
+    let key = aead::OpeningKey::new(ALGORITHM, &derive_key(passphrase, &salt, iterations))
+        .map_err(|_| "Cannot create decryption key".to_string())?;
+
+    let plaintext = aead::open_in_place(&key, &nonce, HEADER.as_bytes(), prefix_len, &mut blob)
+        .map_err(|_| "Cannot decrypt credentials, wrong passphrase or corrupted file".to_string())?;
+
+    return String::from_utf8(plaintext.to_vec())
+        .map_err(|_| "Decrypted credentials are not UTF-8".to_string());
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::derive(&digest::SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+
+    return key;
+}
+
+#[cfg(test)]
+mod tests {
+    use base64;
+    use super::{decrypt, encrypt, is_encrypted, HEADER};
+
+    const CREDENTIALS: &'static str = "aws_access_key_id = \"AKIA\"\naws_secret_access_key = \"secret\"\n";
+
+    // Flip a bit of the decoded blob at `index` and encode it back
+    fn tamper(encrypted: &str, index: usize) -> String {
+        let encoded: &str = encrypted.trim_left()[HEADER.len()..].trim();
+        let mut blob: Vec<u8> = base64::decode(encoded).unwrap();
+        let index: usize = index % blob.len();
+        blob[index] ^= 1;
+
+        return format!("{}\n{}\n", HEADER, base64::encode(&blob));
+    }
+
+    #[test]
+    fn encrypted_content_is_decrypted_with_the_passphrase() {
+        let encrypted: String = encrypt(CREDENTIALS, "passphrase").unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("AKIA"));
+        assert_eq!(CREDENTIALS, decrypt(&encrypted, "passphrase").unwrap());
+    }
+
+    #[test]
+    fn wrong_passphrase_is_refused() {
+        let encrypted: String = encrypt(CREDENTIALS, "passphrase").unwrap();
+
+        let error: String = decrypt(&encrypted, "other passphrase").unwrap_err();
+        assert!(error.contains("wrong passphrase"), "{}", error);
+    }
+
+    #[test]
+    fn tampered_content_is_refused() {
+        let encrypted: String = encrypt(CREDENTIALS, "passphrase").unwrap();
+
+        // Salt, nonce, ciphertext and tag, the iterations are left as is
+        // since a larger count only makes the key derivation slower
+        for index in &[0, 20, 40, usize::max_value()] {
+            assert!(decrypt(&tamper(&encrypted, *index), "passphrase").is_err(), "byte {} changed", index);
+        }
+
+        // Another format version is not read as this one
+        let error: String = decrypt(&encrypted.replacen("v1", "v2", 1), "passphrase").unwrap_err();
+        assert!(error.contains("Missing encrypted credentials header"), "{}", error);
+    }
+
+    #[test]
+    fn plaintext_and_truncated_content_are_refused() {
+        assert!(decrypt(CREDENTIALS, "passphrase").unwrap_err().contains("Missing"));
+        assert!(decrypt(&format!("{}\nAAAA\n", HEADER), "passphrase").unwrap_err().contains("truncated"));
+    }
+}
@@ -0,0 +1,201 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+// Custom CloudWatch Logs endpoint, such as a VPC endpoint or a local
+// stand-in, reached with requests signed for the region of the agent.
+
+use std::io;
+use rusoto_core::{DispatchSignedRequest, HttpDispatchError, HttpResponse, Region, SignedRequest};
+use rusoto_credential::ProvideAwsCredentials;
+
+/// Scheme and host, with its optional port, of an `endpoint_url`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub scheme: String,
+    pub host: String,
+}
+
+impl Endpoint {
+    /// Parse `https://host[:port]` or `http://host[:port]`, a bare host uses https
+    pub fn parse(url: &str) -> Result<Endpoint, String> {
+        let (scheme, rest): (&str, &str) = match url.find("://") {
+            Some(position) => (&url[..position], &url[position + 3..]),
+            None => ("https", url),
+        };
+
+        if scheme != "https" && scheme != "http" {
+            return Err(format!("unsupported scheme {}, use https or http", scheme));
+        }
+
+        let host: &str = rest.trim_right_matches('/');
+        if host.is_empty() {
+            return Err("no host".to_string());
+        }
+
+        if host.contains('/') {
+            return Err("a path is not supported, CloudWatch Logs is served at /".to_string());
+        }
+
+        return Ok(Endpoint {
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+        });
+    }
+}
+
+/// Dispatcher sending the requests of a client to a custom endpoint
+///
+/// rusoto signs with the region of the client, and `Region::Custom` would
+/// put the URL in the credential scope where AWS expects a region name.
+/// The client keeps the real region and each request is signed again here,
+/// with the real credentials, for the host and scheme of the endpoint.
+pub struct EndpointDispatcher<D, P> {
+    dispatcher: D,
+    credentials: P,
+    endpoint: Endpoint,
+    region: Region,
+}
+
+impl<D, P> EndpointDispatcher<D, P> {
+    pub fn new(dispatcher: D, credentials: P, endpoint: Endpoint, region: Region) -> EndpointDispatcher<D, P> {
+        return EndpointDispatcher {
+            dispatcher: dispatcher,
+            credentials: credentials,
+            endpoint: endpoint,
+            region: region,
+        };
+    }
+}
+
+impl<D, P> DispatchSignedRequest for EndpointDispatcher<D, P>
+    where D: DispatchSignedRequest, P: ProvideAwsCredentials
+{
+    fn dispatch(&self, request: &SignedRequest) -> Result<HttpResponse, HttpDispatchError> {
+        let credentials = match self.credentials.credentials() {
+            Ok(credentials) => credentials,
+            Err(why) => return Err(HttpDispatchError::from(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Cannot get AWS credentials : {}", why)
+            ))),
+        };
+
+        let mut endpoint_request = SignedRequest::new(&request.method, &request.service, &self.region, &request.path);
+        endpoint_request.scheme = Some(self.endpoint.scheme.to_owned());
+        endpoint_request.hostname = Some(self.endpoint.host.to_owned());
+        endpoint_request.params = request.params.clone();
+        endpoint_request.payload = request.payload.clone();
+
+        // The signing headers of the client are replaced when signing again
+        endpoint_request.headers = request.headers.clone();
+        endpoint_request.sign_with_plus(&credentials, true);
+
+        return self.dispatcher.dispatch(&endpoint_request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io;
+    use std::str;
+    use rusoto_core::{DispatchSignedRequest, HttpDispatchError, HttpResponse, Region, SignedRequest};
+    use rusoto_credential::{ProvideAwsCredentials, StaticProvider};
+    use super::{Endpoint, EndpointDispatcher};
+
+    // Keep the scheme, host and headers of the request that would be sent
+    struct Recorder {
+        sent: RefCell<Vec<(Option<String>, Option<String>, String, String)>>,
+    }
+
+    impl DispatchSignedRequest for Recorder {
+        fn dispatch(&self, request: &SignedRequest) -> Result<HttpResponse, HttpDispatchError> {
+            let header = |name: &str| str::from_utf8(&request.headers[name][0]).unwrap().to_string();
+            self.sent.borrow_mut().push((
+                request.scheme.clone(),
+                request.hostname.clone(),
+                header("host"),
+                header("authorization"),
+            ));
+
+            return Err(HttpDispatchError::from(io::Error::new(io::ErrorKind::Other, "recorded")));
+        }
+    }
+
+    fn provider(key: &str) -> StaticProvider {
+        return StaticProvider::new(key.to_string(), "secret".to_string(), None, None);
+    }
+
+    #[test]
+    fn parse_endpoints() {
+        assert_eq!(
+            Ok(Endpoint { scheme: "https".to_string(), host: "logs.example.com".to_string() }),
+            Endpoint::parse("https://logs.example.com/")
+        );
+        assert_eq!(
+            Ok(Endpoint { scheme: "http".to_string(), host: "127.0.0.1:4566".to_string() }),
+            Endpoint::parse("http://127.0.0.1:4566")
+        );
+        assert_eq!(
+            Ok(Endpoint { scheme: "https".to_string(), host: "vpce.example.com".to_string() }),
+            Endpoint::parse("vpce.example.com")
+        );
+        assert!(Endpoint::parse("ftp://logs.example.com").is_err());
+        assert!(Endpoint::parse("https://").is_err());
+        assert!(Endpoint::parse("https://logs.example.com/prefix").is_err());
+    }
+
+    #[test]
+    fn requests_are_signed_again_with_the_region() {
+        let endpoint = Endpoint::parse("http://127.0.0.1:4566").unwrap();
+        let dispatcher = EndpointDispatcher::new(
+            Recorder { sent: RefCell::new(vec![]) },
+            provider("real"),
+            endpoint,
+            Region::EuWest1
+        );
+
+        // As the client does, with the placeholder credentials it is given
+        let mut request = SignedRequest::new("POST", "logs", &Region::EuWest1, "/");
+        request.set_payload(Some(b"{}".to_vec()));
+        request.sign_with_plus(&provider("client").credentials().unwrap(), true);
+
+        assert!(dispatcher.dispatch(&request).is_err());
+
+        let sent = dispatcher.dispatcher.sent.borrow();
+        let (ref scheme, ref hostname, ref host, ref authorization) = sent[0];
+        assert_eq!(&Some("http".to_string()), scheme);
+        assert_eq!(&Some("127.0.0.1:4566".to_string()), hostname);
+        assert_eq!("127.0.0.1:4566", host);
+        assert!(authorization.contains("Credential=real/"), "{}", authorization);
+        assert!(authorization.contains("/eu-west-1/logs/aws4_request"), "{}", authorization);
+    }
+}
@@ -32,5 +32,14 @@
 
 pub mod parser;
 pub mod configuration;
+pub mod container;
 pub mod credentials;
-pub mod discovery;
\ No newline at end of file
+pub mod discovery;
+pub mod endpoint;
+pub mod encryption;
+pub mod placeholder;
+pub mod profile;
+pub mod region;
+
+#[cfg(test)]
+mod test_server;
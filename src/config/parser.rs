@@ -31,25 +31,13 @@
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use std::fs::File;
-use std::path::Path;
-use std::error::Error;
 use std::io::prelude::*;
+use error::Error;
 
-pub fn get_file_content(from: String) -> String {
-    let path = Path::new(&from);
-    let display = path.display();
-
-    let mut file = match File::open(&path) {
-        Err(why) => panic!("Couldn't open file path {}: {}", display,
-                           why.description()),
-        Ok(file) => file,
-    };
-
+pub fn get_file_content(from: String) -> Result<String, Error> {
     let mut content = String::new();
-    match file.read_to_string(&mut content) {
-        Err(why) => panic!("Couldn't read file: {}", why.description()),
-        Ok(_) => {},
-    }
-
-    return content;
-}
\ No newline at end of file
+    return match File::open(&from).and_then(|mut file| file.read_to_string(&mut content)) {
+        Err(why) => Err(Error::Io(format!("Couldn't read file {}", from), why)),
+        Ok(_) => Ok(content),
+    };
+}
@@ -0,0 +1,144 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+// Placeholders such as `{instance_id}` allowed in `log_group_name` and
+// `log_stream_name`, replaced once the file to watch is known.
+
+use std::env;
+use std::path::Path;
+use std::process;
+use config::discovery::{self, Metadata};
+
+const ENV_PREFIX: &'static str = "env:";
+const NAMES: [&'static str; 7] = [
+    "instance_id",
+    "hostname",
+    "ip_address",
+    "region",
+    "az",
+    "file_name",
+    "pid",
+];
+
+/// Values of the placeholders common to every log file
+#[derive(Clone, Debug)]
+pub struct Values {
+    pub instance_id: Option<String>,
+    pub hostname: Option<String>,
+    pub ip_address: Option<String>,
+    pub region: String,
+    pub az: Option<String>,
+    pub pid: u32,
+}
+
+impl Values {
+    /// Gather the values from the instance metadata when available,
+    /// from the host otherwise. Only the instance ID has no host value.
+    pub fn new(metadata: Option<&Metadata>, region: String) -> Values {
+        let metadata: Metadata = metadata.cloned().unwrap_or_default();
+
+        return Values {
+            instance_id: metadata.instance_id,
+            hostname: discovery::hostname().or(metadata.hostname),
+            ip_address: metadata.private_ip.or(discovery::ip_address()),
+            region,
+            az: metadata.availability_zone,
+            pid: process::id(),
+        };
+    }
+}
+
+/// Check every placeholder of the template is known
+pub fn validate(template: &str) -> Result<(), String> {
+    return render(template, |name| {
+        if NAMES.contains(&name) || (name.starts_with(ENV_PREFIX) && name.len() > ENV_PREFIX.len()) {
+            Ok(String::new())
+        } else {
+            Err(format!("unknown placeholder {{{}}}", name))
+        }
+    }).map(|_| ());
+}
+
+/// Replace the placeholders of the template for the given file
+///
+/// `{env:NAME}` is replaced by the environment variable `NAME`.
+pub fn expand(template: &str, values: &Values, file: &str) -> Result<String, String> {
+    return render(template, |name| {
+        let value: Option<String> = match name {
+            "instance_id" => match values.instance_id {
+                Some(ref instance_id) => Some(instance_id.to_owned()),
+                None => return Err(
+                    "{instance_id} is unavailable without instance metadata, use {hostname} outside of EC2".to_string()
+                ),
+            },
+            "hostname" => values.hostname.to_owned(),
+            "ip_address" => values.ip_address.to_owned(),
+            "region" => Some(values.region.to_owned()),
+            "az" => values.az.to_owned(),
+            "pid" => Some(values.pid.to_string()),
+            "file_name" => Path::new(file).file_name()
+                .map(|file_name| file_name.to_string_lossy().into_owned()),
+            _ if name.starts_with(ENV_PREFIX) => env::var(&name[ENV_PREFIX.len()..]).ok(),
+            _ => return Err(format!("unknown placeholder {{{}}}", name)),
+        };
+
+        value.ok_or(format!("no value available for placeholder {{{}}}", name))
+    });
+}
+
+// Walk the template and replace each `{name}` by the result of resolve
+fn render<F>(template: &str, resolve: F) -> Result<String, String>
+    where F: Fn(&str) -> Result<String, String>
+{
+    let mut result = String::new();
+    let mut rest: &str = template;
+
+    while let Some(open) = rest.find('{') {
+        result.push_str(&rest[..open]);
+
+        let close: usize = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => return Err(format!("unclosed placeholder in {}", template)),
+        };
+
+        result.push_str(&resolve(&rest[open + 1..close])?);
+        rest = &rest[close + 1..];
+    }
+
+    if rest.contains('}') {
+        return Err(format!("unexpected }} in {}", template));
+    }
+
+    result.push_str(rest);
+
+    return Ok(result);
+}
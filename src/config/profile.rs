@@ -0,0 +1,175 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+// Read the shared AWS files `~/.aws/config` and `~/.aws/credentials`
+// used by the AWS CLI and SDKs.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+const DEFAULT_PROFILE: &'static str = "default";
+
+/// Name of the profile selected by `AWS_PROFILE`, `default` otherwise
+pub fn profile_name() -> String {
+    return env::var("AWS_PROFILE")
+        .or(env::var("AWS_DEFAULT_PROFILE"))
+        .unwrap_or(DEFAULT_PROFILE.to_string());
+}
+
+/// Path of the shared config file, `AWS_CONFIG_FILE` or `~/.aws/config`
+pub fn config_path() -> Option<PathBuf> {
+    return shared_file_path("AWS_CONFIG_FILE", "config");
+}
+
+/// Settings of the profile in the shared config file
+///
+/// Profiles other than default are named `[profile name]` in this file.
+pub fn config_section(profile: &str) -> Option<HashMap<String, String>> {
+    let path: PathBuf = match config_path() {
+        Some(path) => path,
+        None => return None,
+    };
+
+    let section: String = if DEFAULT_PROFILE == profile {
+        profile.to_string()
+    } else {
+        format!("profile {}", profile)
+    };
+
+    return read_section(&path, &section);
+}
+
+/// Path of the shared credentials file, `AWS_SHARED_CREDENTIALS_FILE` or `~/.aws/credentials`
+pub fn credentials_path() -> Option<PathBuf> {
+    return shared_file_path("AWS_SHARED_CREDENTIALS_FILE", "credentials");
+}
+
+/// Settings of the profile in the shared credentials file
+///
+/// Unlike the config file, sections are named after the profile only.
+pub fn credentials_section(profile: &str) -> Option<HashMap<String, String>> {
+    return match credentials_path() {
+        Some(path) => read_section(&path, profile),
+        None => None,
+    };
+}
+
+fn shared_file_path(env_name: &str, file_name: &str) -> Option<PathBuf> {
+    if let Ok(path) = env::var(env_name) {
+        return Some(PathBuf::from(path));
+    }
+
+    return env::home_dir().map(|home| home.join(".aws").join(file_name));
+}
+
+/// Read the `key = value` settings of a section of an INI file
+pub fn read_section(path: &PathBuf, section: &str) -> Option<HashMap<String, String>> {
+    let mut content = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut content)) {
+        Ok(_) => {},
+        Err(_) => return None,
+    }
+
+    let mut values: Option<HashMap<String, String>> = None;
+    let mut in_section: bool = false;
+
+    for raw_line in content.lines() {
+        let line: &str = raw_line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            in_section = section == line[1..line.len() - 1].trim();
+            if in_section && values.is_none() {
+                values = Some(HashMap::new());
+            }
+            continue;
+        }
+
+        if !in_section {
+            continue;
+        }
+
+        if let Some(position) = line.find('=') {
+            let key: String = line[..position].trim().to_string();
+            let value: String = line[position + 1..].trim().to_string();
+
+            if let Some(ref mut values) = values {
+                values.insert(key, value);
+            }
+        }
+    }
+
+    return values;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::read_section;
+
+    fn config_file() -> PathBuf {
+        return PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/profile/config"));
+    }
+
+    #[test]
+    fn default_section_is_named_after_the_profile() {
+        let section = read_section(&config_file(), "default").unwrap();
+
+        assert_eq!(2, section.len());
+        assert_eq!(Some(&"eu-west-1".to_string()), section.get("region"));
+        assert_eq!(Some(&"json".to_string()), section.get("output"));
+    }
+
+    #[test]
+    fn other_sections_are_prefixed_with_profile() {
+        let section = read_section(&config_file(), "profile logs").unwrap();
+
+        assert_eq!(3, section.len());
+        assert_eq!(Some(&"arn:aws:iam::123456789012:role/logs".to_string()), section.get("role_arn"));
+        assert_eq!(Some(&"default".to_string()), section.get("source_profile"));
+        assert_eq!(Some(&"shipper".to_string()), section.get("role_session_name"));
+
+        // The credentials file style name is another section
+        assert_eq!(Some(&"us-east-1".to_string()), read_section(&config_file(), "logs").unwrap().get("region"));
+    }
+
+    #[test]
+    fn missing_section_or_file_is_none() {
+        assert!(read_section(&config_file(), "profile missing").is_none());
+        assert!(read_section(&PathBuf::from("/nonexistent/awatchlog/config"), "default").is_none());
+    }
+}
@@ -0,0 +1,76 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::env;
+use std::str::FromStr;
+use rusoto_core::Region;
+use config::discovery::Metadata;
+use config::profile;
+
+/// Resolve the region to send logs to
+///
+/// Look in order at the `region` of the configuration, the `AWS_REGION`
+/// and `AWS_DEFAULT_REGION` variables, the profile of `~/.aws/config`
+/// and the instance metadata. The first value found must be valid.
+pub fn resolve(configured: Option<&String>, metadata: Option<&Metadata>) -> Result<Region, String> {
+    let candidates: Vec<(String, Option<String>)> = vec![
+        ("configuration".to_string(), configured.cloned()),
+        ("AWS_REGION".to_string(), env::var("AWS_REGION").ok()),
+        ("AWS_DEFAULT_REGION".to_string(), env::var("AWS_DEFAULT_REGION").ok()),
+        (
+            format!("profile {} of the AWS config file", profile::profile_name()),
+            profile::config_section(&profile::profile_name())
+                .and_then(|section| section.get("region").cloned()),
+        ),
+        (
+            "instance metadata".to_string(),
+            metadata.and_then(|metadata| metadata.region.to_owned()),
+        ),
+    ];
+
+    for (source, value) in candidates {
+        let value: String = match value {
+            Some(ref value) if !value.trim().is_empty() => value.trim().to_string(),
+            _ => continue,
+        };
+
+        return match Region::from_str(&value) {
+            Ok(region) => {
+                println!("Region {} found in {}", region, source);
+                Ok(region)
+            },
+            Err(_) => Err(format!("Invalid region {} found in {}", value, source)),
+        };
+    }
+
+    return Err("No region found, set region in the [general] section of the configuration".to_string());
+}
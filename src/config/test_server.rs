@@ -0,0 +1,142 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+// Local HTTP stand-in of the AWS services queried over plain HTTP, such as
+// the instance metadata or the container credentials endpoint.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    /// Value of the header, its name compared case insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        return self.headers.iter()
+            .find(|&&(ref header, _)| header.to_lowercase() == name.to_lowercase())
+            .map(|&(_, ref value)| value.as_str());
+    }
+}
+
+pub struct Server {
+    /// Base URL, such as `http://127.0.0.1:34567`
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    /// Listen on a free local port, each request gets the status and body
+    /// returned by `answer`. The server lives as long as the test process.
+    pub fn start<F>(answer: F) -> Server
+        where F: Fn(&Request) -> (u16, String) + Send + 'static
+    {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url: String = format!("http://{}", listener.local_addr().unwrap());
+        let requests: Arc<Mutex<Vec<Request>>> = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream: TcpStream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let request: Request = match read_request(&stream) {
+                    Some(request) => request,
+                    None => continue,
+                };
+
+                let (status, body) = answer(&request);
+                recorded.lock().unwrap().push(request);
+
+                let response: String = format!(
+                    "HTTP/1.1 {} Stand-in\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).ok();
+            }
+        });
+
+        return Server { url, requests };
+    }
+
+    /// Requests answered so far, in order
+    pub fn requests(&self) -> Vec<Request> {
+        return self.requests.lock().unwrap().clone();
+    }
+}
+
+// Read the request line, headers and body
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method: String = parts.next()?.to_string();
+    let path: String = parts.next()?.to_string();
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+
+        let header: &str = line.trim_right();
+        if header.is_empty() {
+            break;
+        }
+
+        let colon: usize = header.find(':')?;
+        headers.push((header[..colon].trim().to_string(), header[colon + 1..].trim().to_string()));
+    }
+
+    let mut request = Request { method, path, headers, body: String::new() };
+    let length: usize = request.header("Content-Length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body: Vec<u8> = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    request.body = String::from_utf8_lossy(&body).into_owned();
+
+    return Some(request);
+}
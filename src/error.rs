@@ -0,0 +1,92 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Failure of the agent, the variant tells which part failed
+#[derive(Debug)]
+pub enum Error {
+    /// Configuration file missing, unreadable or invalid
+    Config(String),
+    /// Credentials file invalid, undecryptable or readable by others,
+    /// failing to fetch credentials is an `Aws` error
+    Credentials(String),
+    /// State file unreadable or not writable
+    State(String),
+    /// Log file not readable, with what was being done
+    Io(String, io::Error),
+    /// Request refused by AWS or not sent, credentials included
+    Aws(String),
+}
+
+impl Error {
+    /// Tell if the error stops the agent, the others only affect one file
+    pub fn is_fatal(&self) -> bool {
+        return match *self {
+            Error::Config(_) | Error::Credentials(_) => true,
+            Error::State(_) | Error::Io(_, _) | Error::Aws(_) => false,
+        };
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Config(ref message) => write!(f, "Configuration error: {}", message),
+            Error::Credentials(ref message) => write!(f, "Credentials error: {}", message),
+            Error::State(ref message) => write!(f, "State error: {}", message),
+            Error::Io(ref message, ref why) => write!(f, "IO error: {} : {}", message, why),
+            Error::Aws(ref message) => write!(f, "AWS error: {}", message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Config(ref message) => message,
+            Error::Credentials(ref message) => message,
+            Error::State(ref message) => message,
+            Error::Io(ref message, _) => message,
+            Error::Aws(ref message) => message,
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(_, ref why) => Some(why),
+            _ => None,
+        }
+    }
+}
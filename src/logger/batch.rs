@@ -0,0 +1,228 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::cmp;
+use rusoto_logs::InputLogEvent;
+
+/// Largest request, the overhead of each event included
+pub const MAX_BATCH_SIZE: usize = 1048576;
+pub const MAX_BATCH_EVENTS: usize = 10000;
+/// Bytes counted for each event on top of its message
+pub const EVENT_OVERHEAD: usize = 26;
+/// Largest event, its overhead included
+pub const MAX_EVENT_SIZE: usize = 262144;
+/// Longest time between the oldest and the newest event of a request
+const MAX_BATCH_SPAN_MILLISECONDS: i64 = 24 * 3600 * 1000;
+
+/// Event built from the log file
+pub struct Entry {
+    pub event: InputLogEvent,
+    /// Offset in the file right after the message of the event
+    pub end_offset: u64,
+    /// End of the message cut off to fit in the event
+    pub truncated: Option<String>,
+}
+
+/// Events sent in a single PutLogEvents request
+pub struct Batch {
+    /// Sorted chronologically
+    pub events: Vec<InputLogEvent>,
+    /// Offset and timestamp of the last entry taken, in file order
+    pub end_offset: u64,
+    pub last_timestamp: i64,
+    /// Every entry has been taken
+    pub complete: bool,
+    /// Parts cut off the messages taken, with the timestamp of their event
+    pub truncated: Vec<InputLogEvent>,
+}
+
+/// Take the longest run of entries, in file order, a single request accepts
+///
+/// The run is cut before reaching 10,000 events, 1 MB counting the overhead
+/// of each event, or a span of more than 24 hours. Entries left out are read
+/// again from `end_offset`, so the offset saved after sending the batch
+/// never goes past an event which has not been sent.
+pub fn first(entries: Vec<Entry>) -> Option<Batch> {
+    let count: usize = entries.len();
+    let mut events: Vec<InputLogEvent> = Vec::new();
+    let mut truncated: Vec<InputLogEvent> = Vec::new();
+    let mut size: usize = 0;
+    let mut oldest: i64 = i64::max_value();
+    let mut newest: i64 = i64::min_value();
+    let mut end_offset: u64 = 0;
+    let mut last_timestamp: i64 = 0;
+
+    for entry in entries {
+        let event_size: usize = entry.event.message.len() + EVENT_OVERHEAD;
+        let timestamp: i64 = entry.event.timestamp;
+
+        if !events.is_empty() {
+            let span: i64 = cmp::max(newest, timestamp) - cmp::min(oldest, timestamp);
+
+            if MAX_BATCH_EVENTS == events.len()
+                || MAX_BATCH_SIZE < size + event_size
+                || MAX_BATCH_SPAN_MILLISECONDS < span {
+                break;
+            }
+        }
+
+        size += event_size;
+        oldest = cmp::min(oldest, timestamp);
+        newest = cmp::max(newest, timestamp);
+        end_offset = entry.end_offset;
+        last_timestamp = timestamp;
+        if let Some(message) = entry.truncated {
+            truncated.push(InputLogEvent { message, timestamp });
+        }
+        events.push(entry.event);
+    }
+
+    if events.is_empty() {
+        return None;
+    }
+
+    // Stable, events sharing a timestamp keep the order of the file
+    events.sort_by_key(|event| event.timestamp);
+
+    return Some(Batch {
+        complete: count == events.len(),
+        events,
+        end_offset,
+        last_timestamp,
+        truncated,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use rusoto_logs::InputLogEvent;
+    use super::{first, Batch, Entry, EVENT_OVERHEAD, MAX_BATCH_EVENTS, MAX_BATCH_SIZE};
+
+    const HOUR: i64 = 3600 * 1000;
+
+    fn entry(message: &str, timestamp: i64, end_offset: u64) -> Entry {
+        return Entry {
+            event: InputLogEvent { message: message.to_string(), timestamp },
+            end_offset,
+            truncated: None,
+        };
+    }
+
+    fn messages(batch: &Batch) -> Vec<&str> {
+        return batch.events.iter().map(|event| event.message.as_str()).collect();
+    }
+
+    #[test]
+    fn no_entry_no_batch() {
+        assert!(first(vec![]).is_none());
+    }
+
+    #[test]
+    fn all_entries_fit() {
+        let batch: Batch = first(vec![entry("a", 1, 2), entry("b", 2, 4), entry("c", 3, 6)]).unwrap();
+
+        assert!(batch.complete);
+        assert_eq!(vec!["a", "b", "c"], messages(&batch));
+        assert_eq!(6, batch.end_offset);
+        assert_eq!(3, batch.last_timestamp);
+    }
+
+    #[test]
+    fn cut_at_max_events() {
+        let entries: Vec<Entry> = (0..MAX_BATCH_EVENTS + 1)
+            .map(|index| entry("x", 0, index as u64 + 1))
+            .collect();
+        let batch: Batch = first(entries).unwrap();
+
+        assert!(!batch.complete);
+        assert_eq!(MAX_BATCH_EVENTS, batch.events.len());
+        assert_eq!(MAX_BATCH_EVENTS as u64, batch.end_offset);
+    }
+
+    #[test]
+    fn cut_at_max_size_counting_the_overhead() {
+        // 1024 events of 1 KB overhead included make exactly 1 MB
+        let message: String = "x".repeat(1024 - EVENT_OVERHEAD);
+        let entries: Vec<Entry> = (0..1025).map(|index| entry(&message, 0, index + 1)).collect();
+        let batch: Batch = first(entries).unwrap();
+
+        assert!(!batch.complete);
+        assert_eq!(1024, batch.events.len());
+        assert_eq!(MAX_BATCH_SIZE, batch.events.iter().map(|event| event.message.len() + EVENT_OVERHEAD).sum::<usize>());
+        assert_eq!(1024, batch.end_offset);
+    }
+
+    #[test]
+    fn cut_past_a_day() {
+        let batch: Batch = first(vec![
+            entry("start", 10 * HOUR, 1),
+            entry("older", 0, 2),
+            entry("a day after older", 24 * HOUR, 3),
+            entry("past a day", 24 * HOUR + 1, 4),
+        ]).unwrap();
+
+        assert!(!batch.complete);
+        assert_eq!(vec!["older", "start", "a day after older"], messages(&batch));
+        assert_eq!(3, batch.end_offset);
+        assert_eq!(24 * HOUR, batch.last_timestamp);
+    }
+
+    #[test]
+    fn sorted_in_file_order_for_equal_timestamps() {
+        let batch: Batch = first(vec![
+            entry("second", 2, 1),
+            entry("first a", 1, 2),
+            entry("first b", 1, 3),
+            entry("first c", 1, 4),
+        ]).unwrap();
+
+        assert_eq!(vec!["first a", "first b", "first c", "second"], messages(&batch));
+        // Last in file order, not the newest
+        assert_eq!(1, batch.last_timestamp);
+        assert_eq!(4, batch.end_offset);
+    }
+
+    #[test]
+    fn truncated_parts_of_the_entries_taken() {
+        let mut cut: Entry = entry("head", 5, 1);
+        cut.truncated = Some("tail".to_string());
+        let mut left_out: Entry = entry("later", 25 * HOUR + 5, 2);
+        left_out.truncated = Some("not taken".to_string());
+
+        let batch: Batch = first(vec![cut, left_out]).unwrap();
+
+        assert!(!batch.complete);
+        assert_eq!(1, batch.truncated.len());
+        assert_eq!("tail", batch.truncated[0].message);
+        assert_eq!(5, batch.truncated[0].timestamp);
+    }
+}
@@ -0,0 +1,127 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::fs::{File, Metadata};
+use std::os::unix::fs::MetadataExt;
+use glob;
+use sha1;
+use logger::source::{LogSource};
+use error::Error;
+
+/// Device and inode identifying a file whatever its path
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FileId {
+    pub device: u64,
+    pub inode: u64,
+}
+
+impl FileId {
+    pub fn from_metadata(metadata: &Metadata) -> FileId {
+        return FileId {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+        };
+    }
+
+    pub fn from_file(file: &File) -> Option<FileId> {
+        return file.metadata().ok().map(|metadata| FileId::from_metadata(&metadata));
+    }
+}
+
+/// Open the file behind the path if it is not the one already opened
+///
+/// Return None while the path still refers to the same file, or when it
+/// does not exist yet, e.g. between the rename and the creation done by
+/// logrotate.
+pub fn reopen_if_rotated(path: &str, current: FileId) -> Option<(File, FileId)> {
+    let file: File = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+
+    return match FileId::from_file(&file) {
+        Some(id) if id != current => Some((file, id)),
+        _ => None,
+    };
+}
+
+/// SHA-1 of the first `size` bytes of the file
+///
+/// Return None while the file is smaller than `size`, the fingerprint
+/// would change as soon as the file grows otherwise. Compressed files
+/// are fingerprinted on their decompressed content, so a rotated archive
+/// is recognized as the file it comes from.
+pub fn fingerprint(source: &mut LogSource, size: u64) -> Option<String> {
+    let mut buffer: Vec<u8> = vec![0; size as usize];
+    let mut read: usize = 0;
+
+    while read < buffer.len() {
+        match source.read_at(&mut buffer[read..], read as u64) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => read += n,
+        }
+    }
+
+    let mut hash = sha1::Sha1::new();
+    hash.update(&buffer);
+
+    return Some(hash.digest().to_string());
+}
+
+/// Tell if the `file` setting is a glob pattern rather than a literal path
+pub fn is_pattern(file: &str) -> bool {
+    return file.contains('*') || file.contains('?') || file.contains('[');
+}
+
+/// Expand a glob pattern, `**` included, into the matching file paths
+///
+/// Directories and unreadable entries are skipped.
+pub fn expand(pattern: &str) -> Result<Vec<String>, Error> {
+    let paths = match glob::glob(pattern) {
+        Err(why) => return Err(Error::Config(format!("Invalid file pattern {} : {}", pattern, why))),
+        Ok(paths) => paths,
+    };
+
+    let mut files: Vec<String> = Vec::new();
+    for entry in paths {
+        match entry {
+            Ok(path) => {
+                if path.is_file() {
+                    files.push(path.to_string_lossy().into_owned());
+                }
+            },
+            Err(why) => println!("Cannot read path matching {} : {}", pattern, why),
+        }
+    }
+
+    return Ok(files);
+}
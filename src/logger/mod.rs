@@ -31,130 +31,375 @@
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 pub mod state;
-
+pub mod timestamp;
+pub mod multiline;
+pub mod files;
+pub mod source;
+pub mod retry;
+pub mod rejected;
+pub mod batch;
+
+use std::cmp;
 use std::str;
-use std::error::Error;
 use std::fs::File;
-use std::os::unix::fs::FileExt;
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use shuteye::sleep;
 
 use chrono::{DateTime, Utc};
-use config::configuration::{ConfigLogFile};
+use error::Error;
+use config::configuration::{ConfigLogFile, TimestampFallback};
+use logger::multiline::{StartPattern};
+use logger::files::{FileId};
+use logger::source::{LogSource};
+use logger::retry::{RetryPolicy};
+use logger::rejected::{Counters, Reason, RejectedEvent};
+use logger::batch::{Batch, Entry};
 use rusoto_logs::{
     CloudWatchLogs,
     CreateLogGroupRequest,
     CreateLogStreamRequest,
+    DescribeLogStreamsRequest,
     InputLogEvent,
     PutLogEventsRequest,
     PutLogEventsError,
 };
 
-const AWS_MAX_BATCH_SIZE: u64 = 788576; // 1048576 - (10000 * 26)
-const AWS_MAX_BATCH_EVENTS: u64 = 10000;
+// Bytes read at once, larger chunks could not be sent in a single request
+const READ_BUFFER_SIZE: usize = batch::MAX_BATCH_SIZE;
 
-pub fn watch(log_file: ConfigLogFile, client: &Box<CloudWatchLogs>) {
+/// Ship the logfile forever, return only when it cannot be read or sent
+///
+/// With `stop_when_removed`, also return once the file has been removed
+/// and read to its end, instead of waiting for it to be created again.
+pub fn watch(
+    log_file: ConfigLogFile,
+    client: &Box<CloudWatchLogs>,
+    retry: RetryPolicy,
+    stop_when_removed: bool
+) -> Result<(), Error> {
     println!("File: {}", log_file.file);
     println!("Group name: {}", log_file.log_group_name);
     println!("Stream Name: {}", log_file.log_stream_name);
     println!("Datetime: {}", log_file.datetime_format);
+    println!("Time zone: {}", log_file.time_zone);
+
+    let start_pattern: Option<StartPattern> = match (
+        &log_file.multi_line_start_pattern,
+        log_file.multi_line_preset
+    ) {
+        (&Some(_), Some(_)) => {
+            return Err(Error::Config(format!(
+                "multi_line_start_pattern and multi_line_preset cannot be used together on {}",
+                log_file.file
+            )));
+        },
+        (&Some(ref pattern), None) => {
+            println!("Multi line start pattern: {}", pattern);
+            match StartPattern::new(pattern, &log_file.datetime_format, &log_file.time_zone) {
+                Ok(start_pattern) => Some(start_pattern),
+                Err(why) => return Err(Error::Config(format!(
+                    "Invalid multi_line_start_pattern {} : {}",
+                    pattern,
+                    why
+                ))),
+            }
+        },
+        (&None, Some(preset)) => {
+            println!("Multi line preset: {:?}", preset);
+            Some(StartPattern::from_preset(preset))
+        },
+        (&None, None) => None,
+    };
 
     create_group(&log_file.log_group_name, client);
     create_stream(&log_file.log_group_name, &log_file.log_stream_name, client);
 
     // Infinite loop
-    consumer(&log_file, client);
+    return consumer(&log_file, &start_pattern, client, retry, stop_when_removed);
 }
 
 /// Consumer is the method used to read from file and
-fn consumer(log_file: &ConfigLogFile, client: &Box<CloudWatchLogs>)
-{
+fn consumer(
+    log_file: &ConfigLogFile,
+    start_pattern: &Option<StartPattern>,
+    client: &Box<CloudWatchLogs>,
+    retry: RetryPolicy,
+    stop_when_removed: bool
+) -> Result<(), Error> {
     // TODO must have the general config to set the custom states_dir
     let states_dir: Option<String> = None;
     let mut token: Option<String> = None;
     let mut offset: u64 = 0;
-    let mut buffer_size: u64 = 16384;
+    let mut last_timestamp: Option<i64> = None;
+    let flush_timeout = Duration::from_millis(log_file.multi_line_flush_timeout_ms);
+    // Offset, size and age of the last multi line event kept in the file
+    let mut pending: Option<(u64, usize, Instant)> = None;
+    let mut rejections: Counters = Counters::default();
+    let dead_letter_path: String = match log_file.dead_letter_file {
+        Some(ref path) => path.to_owned(),
+        None => state::get_dead_letter_file_path(log_file.file.to_owned(), states_dir.to_owned())?,
+    };
+
+    // The file is kept open, so once rotated by rename the end
+    // of the old file can still be read through this handle.
+    let mut source: LogSource = LogSource::new(open_file(&log_file.file)?, &log_file.file);
+    let mut file_id: FileId = match source.file().metadata() {
+        Ok(metadata) => FileId::from_metadata(&metadata),
+        Err(why) => return Err(Error::Io(format!("Cannot stat logfile {}", log_file.file), why)),
+    };
+
+    let mut fingerprint: Option<String> = log_file.fingerprint_size
+        .and_then(|size| files::fingerprint(&mut source, size));
 
-    match state::load(log_file.file.to_owned(), states_dir.to_owned()) {
-        Ok(state) => {
-            token = Some(state.token);
-            offset = state.offset;
+    match state::load(log_file.file.to_owned(), states_dir.to_owned())? {
+        Some(state) => {
+            token = Some(state.token.to_owned());
+
+            if state.matches(file_id, fingerprint.as_ref()) {
+                offset = state.offset;
+            } else {
+                // Finish the file rotated while the agent was down, the loop
+                // moves on to the live file once it has been drained.
+                match find_rotated(log_file, &state)? {
+                    Some((rotated_path, rotated_source, rotated_file_id)) => {
+                        println!("File {} has been rotated since the last run, resume {} at offset {}",
+                                 log_file.file, rotated_path, state.offset);
+                        source = rotated_source;
+                        file_id = rotated_file_id;
+                        offset = state.offset;
+                        fingerprint = state.fingerprint.to_owned();
+                    },
+                    None => {
+                        println!("File {} has been rotated since the last run, read it from start",
+                                 log_file.file);
+                    },
+                }
+            }
         },
-        Err(e) => {
-            if e.code != 0 {
-                panic!("TODO Error unexpected... {}", e.message);
+        None => {},
+    }
+
+    // The stream knows the token it expects, the stored one is stale when the
+    // state is missing, has been restored, or another agent wrote to the stream.
+    match upload_sequence_token(&log_file.log_group_name, &log_file.log_stream_name, client, retry) {
+        Ok(upload_token) => {
+            if upload_token != token {
+                if token.is_some() {
+                    println!("WARNING: stored sequence token of {} differs from the one expected by {}/{}, use the latter",
+                             log_file.file, log_file.log_group_name, log_file.log_stream_name);
+                }
+
+                token = upload_token;
             }
         },
+        Err(why) => println!("WARNING: {}, start with the stored sequence token", why),
     }
 
+    // Allocated once, every read of the file goes through it
+    let mut read_buffer: Vec<u8> = vec![0; READ_BUFFER_SIZE];
+
     loop {
         let mut _offset: u64 = offset;
-        let buf_size = buffer_size;
         let mut delay = Duration::new(5, 0);
-        let content: String = read_file(&log_file.file, &mut _offset, buf_size);
+        let content: String = read_file(&mut source, &log_file.file, &mut _offset, &mut read_buffer)?;
+        // NUL bytes may have been skipped before the content
+        let content_offset: u64 = _offset - content.len() as u64;
 
         {
-            let delta: u64 = 256;
-            let content_size = content.len() as u64;
-
             // Wait and continue loop if message empty
-            if 0 == content_size {
+            if content.is_empty() {
+                // Only NUL bytes have been skipped, keep reading after them
+                if _offset != offset {
+                    offset = _offset;
+                    continue;
+                }
+
+                // File truncated in place (copytruncate or `> app.log`),
+                // offsets of compressed files are not comparable to their size
+                let file_size: u64 = source.file().metadata().map(|metadata| metadata.len()).unwrap_or(offset);
+                if !source.is_compressed() && file_size < offset {
+                    println!("WARNING: file {} has been truncated to {} bytes below offset {}, read it from start",
+                             log_file.file, file_size, offset);
+                    offset = 0;
+                    pending = None;
+                    fingerprint = None;
+                    continue;
+                }
+
+                // Old file drained, switch to the new one if it has been rotated
+                if let Some((new_file, new_file_id)) = files::reopen_if_rotated(&log_file.file, file_id) {
+                    println!("File {} has been rotated, read the new one from start", log_file.file);
+
+                    source = LogSource::new(new_file, &log_file.file);
+                    file_id = new_file_id;
+                    offset = 0;
+                    pending = None;
+                    fingerprint = log_file.fingerprint_size
+                        .and_then(|size| files::fingerprint(&mut source, size));
+
+                    if let Some(ref token) = token {
+                        state::save(log_file.file.to_owned(), states_dir.to_owned(), state::State {
+                            token: token.to_owned(),
+                            offset,
+                            device: Some(file_id.device),
+                            inode: Some(file_id.inode),
+                            fingerprint: fingerprint.to_owned(),
+                        })?;
+                    }
+
+                    continue;
+                }
+
+                // Drained and gone, a file matched by a pattern is not waited for
+                if stop_when_removed && !Path::new(&log_file.file).exists() {
+                    println!("File {} has been removed, stop watching it", log_file.file);
+                    return Ok(());
+                }
+
                 sleep(delay);
                 continue;
             }
+        }
 
-            // Ensure the number of lines does not reach 10K limit
-            if AWS_MAX_BATCH_EVENTS <= (content.lines().size_hint().0 as u64) {
-                // Divide by 2 in order reduce drastically the size
-                // and re scale-up progressively if needs
-                buffer_size = buffer_size / 2;
-                continue;
-            }
+        // Each message comes with the offset right after it in the file
+        let messages: Vec<(String, u64)> = match *start_pattern {
+            None => {
+                let mut position: u64 = content_offset;
 
-            // Reduce buffer size if lower than expected (because can be truncated)
-            if content_size < (buffer_size - delta) {
-                buffer_size = content_size - delta;
-            } else {
-                // Otherwise increase buffer size by 50%
-                buffer_size = content_size * 150 / 100;
+                content.split('\n').map(|line| {
+                    position = cmp::min(position + line.len() as u64 + 1, _offset);
+                    (line.trim_right_matches('\r').to_string(), position)
+                }).collect()
+            },
+            Some(ref pattern) => {
+                let mut groups = multiline::group(&content, pattern);
+                let mut ends: Vec<u64> = groups.iter()
+                    .skip(1)
+                    .map(|group| content_offset + group.start as u64)
+                    .collect();
+                ends.push(_offset);
+
+                // The last event may continue in the next chunk, it is left
+                // in the file until another event starts after it, or nothing
+                // has been appended to it during the flush timeout.
+                if let Some(tail_start) = groups.last().map(|tail| tail.start) {
+                    let tail_offset: u64 = content_offset + tail_start as u64;
+                    let tail_size: usize = content.len() - tail_start;
+                    let buffer_full: bool = 1 == groups.len() && read_buffer.len() <= content.len() + 256;
+
+                    let same_tail: bool = match pending {
+                        Some((pending_offset, pending_size, _)) => {
+                            pending_offset == tail_offset && pending_size == tail_size
+                        },
+                        None => false,
+                    };
+                    let expired: bool = match pending {
+                        Some((_, _, since)) => same_tail && since.elapsed() >= flush_timeout,
+                        None => false,
+                    };
 
-                // Ensure to not allocate more than Max batch size
-                if AWS_MAX_BATCH_SIZE < buffer_size {
-                    buffer_size = AWS_MAX_BATCH_SIZE;
+                    if buffer_full || expired {
+                        pending = None;
+                    } else {
+                        if !same_tail {
+                            pending = Some((tail_offset, tail_size, Instant::now()));
+                        }
+
+                        groups.pop();
+                        ends.pop();
+                        _offset = tail_offset;
+                    }
                 }
-            }
+
+                groups.into_iter().map(|group| group.message).zip(ends).collect()
+            },
+        };
+
+        if messages.is_empty() {
+            sleep(delay);
+            continue;
+        }
+
+        let mut _last_timestamp: Option<i64> = last_timestamp;
+        let entries: Vec<Entry> = build_events(&messages, log_file, &mut _last_timestamp);
+
+        let batch: Batch = match batch::first(entries) {
+            Some(batch) => batch,
+            // Only empty lines, there is nothing to send
+            None => {
+                offset = _offset;
+                last_timestamp = _last_timestamp;
+                continue;
+            },
+        };
+
+        // The entries left out are read again on the next turn
+        if !batch.complete {
+            _offset = batch.end_offset;
+            _last_timestamp = Some(batch.last_timestamp);
         }
 
-        println!("the buffer size are : {}", buf_size);
-        println!("the next buffer size are : {}", buffer_size);
+        let truncated: Vec<RejectedEvent> = batch.truncated.into_iter()
+            .map(|event| RejectedEvent { reason: Reason::Truncated, event })
+            .collect();
 
         match put_log_events(
-            &content,
+            batch.events,
             &log_file.log_group_name,
             &log_file.log_stream_name,
             token,
-            client
+            client,
+            retry
         ) {
             Ok(LogEventResponse) => {
-                token = LogEventResponse.token;
+                token = Some(LogEventResponse.token.to_owned());
+
+                // Sending them again would be refused as well, the offset moves past
+                // them even when they cannot be kept in the dead-letter file
+                let mut dead_letters: Vec<RejectedEvent> = LogEventResponse.rejected;
+                if !dead_letters.is_empty() {
+                    rejections.add(&dead_letters);
+                    println!("WARNING: {} events of {} rejected by {}/{} ({} so far), appended to {}",
+                             dead_letters.len(), log_file.file, log_file.log_group_name,
+                             log_file.log_stream_name, rejections, dead_letter_path);
+                }
+
+                rejections.add(&truncated);
+                dead_letters.extend(truncated);
+                if !dead_letters.is_empty() {
+                    if let Err(why) = rejected::append(&dead_letter_path, &dead_letters) {
+                        println!("WARNING: {} rejected or truncated events of {} are lost : {}",
+                                 dead_letters.len(), log_file.file, why);
+                    }
+                }
+
+                // The file may have been too small to be fingerprinted so far
+                if fingerprint.is_none() {
+                    fingerprint = log_file.fingerprint_size
+                        .and_then(|size| files::fingerprint(&mut source, size));
+                }
+
                 state::save(log_file.file.to_owned(), states_dir.to_owned(), state::State {
-                    token: token.to_owned().unwrap(),
+                    token: LogEventResponse.token,
                     offset: _offset,
-                });
+                    device: Some(file_id.device),
+                    inode: Some(file_id.inode),
+                    fingerprint: fingerprint.to_owned(),
+                })?;
 
                 // Waiter in milliseconds
                 delay = Duration::new(0, 400*1000000);
                 offset = _offset;
+                last_timestamp = _last_timestamp;
             },
-            Err(LogEventError) => {
-                token = LogEventError.token;
+            Err(LogEventError::InvalidSequenceToken(expected_token)) => {
+                token = expected_token;
             },
+            Err(LogEventError::Failed(why)) => return Err(why),
         }
 
         // TODO pause of x ms depending of the size of vector
-        println!("\n-----------------------------\n");
-
         sleep(delay);
     }
 }
@@ -169,7 +414,7 @@ fn create_group(log_group_name: &String, client: &Box<CloudWatchLogs>) {
 
     match result {
         //TODO find how to match only CreateLogStreamError::ResourceAlreadyExists
-        Err(why) => println!("The creation of log group have failed: {}", why.description()),
+        Err(why) => println!("The creation of log group have failed: {}", why),
         Ok(_) => println!("Log group {} created with success", log_group_name),
     }
 }
@@ -187,92 +432,212 @@ fn create_stream(
     let result = client.create_log_stream(&log_stream_request);
 
     match result {
-        Err(why) => println!("The creation of log stream have failed: {}", why.description()),
+        Err(why) => println!("The creation of log stream have failed: {}", why),
         Ok(_) => println!("Log stream {} create with success", log_stream_name),
     }
 }
 
-/// Read the log file name at specific position
+/// Search among the files matching `rotated_pattern` the one the state
+/// applies to, recognized by its inode or its fingerprint.
+fn find_rotated(log_file: &ConfigLogFile, state: &state::State) -> Result<Option<(String, LogSource, FileId)>, Error> {
+    let pattern: &String = match log_file.rotated_pattern {
+        Some(ref pattern) => pattern,
+        None => return Ok(None),
+    };
+
+    for path in files::expand(pattern)? {
+        let mut candidate: LogSource = match File::open(&path) {
+            Ok(file) => LogSource::new(file, &path),
+            Err(_) => continue,
+        };
+        let candidate_id: FileId = match FileId::from_file(candidate.file()) {
+            Some(id) => id,
+            None => continue,
+        };
+        let candidate_fingerprint: Option<String> = log_file.fingerprint_size
+            .and_then(|size| files::fingerprint(&mut candidate, size));
+
+        // The fingerprint wins when known: a file copied by the rotation
+        // gets a new inode, and an inode may be recycled for another file.
+        let matched: bool = match (state.fingerprint.as_ref(), candidate_fingerprint.as_ref()) {
+            (Some(expected), Some(current)) => expected == current,
+            _ => state.device == Some(candidate_id.device) && state.inode == Some(candidate_id.inode),
+        };
+
+        if matched {
+            return Ok(Some((path, candidate, candidate_id)));
+        }
+    }
+
+    return Ok(None);
+}
+
+fn open_file(file_name: &String) -> Result<File, Error> {
+    return File::open(Path::new(file_name))
+        .map_err(|why| Error::Io(format!("Cannot open logfile {}", file_name), why));
+}
+
+/// Read the log file at specific position
 ///
 /// Return String reads or None if eof reached
 /// 
 /// The offset is relative to the start of the file and thus independent
 /// from the current cursor.
-fn read_file(file_name: &String, offset: &mut u64, buf_size: u64) -> String {
+fn read_file(source: &mut LogSource, file_name: &String, offset: &mut u64, buffer: &mut [u8]) -> Result<String, Error> {
     let path = Path::new(file_name);
     let path_display = path.display();
-    let file = match File::open(&path) {
-        Err(why) => panic!("ERROR: cannot open logfile {} : {}",
-                           path_display, why.description()),
-        Ok(file) => file,
-    };
-
-    fn new_buffer(size: u64) -> Vec<u8> {
-        vec![0; size as usize]
-    }
+    let content: String;
 
-    let mut buf_sized = new_buffer(buf_size);
-    let mut buffer = buf_sized.as_mut_slice();
-    let mut content: String;
-
-    match file.read_at(&mut buffer, offset.to_owned()) {
-        Err(why) => panic!("couldn't read {} : {}", path_display, why.description()),
+    match source.read_at(buffer, offset.to_owned()) {
+        Err(why) => return Err(Error::Io(format!("Couldn't read {}", path_display), why)),
         Ok(n) => {
-            content = str::from_utf8(&buffer[..n]).unwrap().to_string();
+            // A copytruncate racing with the writer leaves a sparse
+            // region filled with NUL bytes where the writer resumes.
+            let nul_size: usize = buffer[..n].iter().take_while(|byte| 0 == **byte).count();
+            if 0 < nul_size {
+                println!("WARNING: skip {} NUL bytes in {} at offset {}",
+                         nul_size, path_display, offset);
+                *offset += nul_size as u64;
+            }
 
-            if let Some(line_feed_offset) = content.rfind("\n") {
-                content.truncate(line_feed_offset);
+            // Cut at the last line feed before decoding, the chunk may end
+            // in the middle of a multi-byte character of an unfinished line.
+            let mut bytes: &[u8] = &buffer[nul_size..n];
+            if let Some(line_feed_offset) = bytes.iter().rposition(|byte| b'\n' == *byte) {
+                bytes = &bytes[..line_feed_offset];
             }
 
-            *offset += content.len() as u64;
+            let (text, invalid_size) = decode_lossy(bytes);
+            if 0 < invalid_size {
+                println!("WARNING: replace {} invalid UTF-8 bytes with ? in {} after offset {}",
+                         invalid_size, path_display, offset);
+            }
+            content = text;
 
-            /*println!("the size of content {} are : {}", path_display, n);
-            println!("the size of content truncate {} are : {}", path_display, content.len());*/
-            println!("the offset are now at : {}", offset);
-            //println!("The content of file are : {:?}", content);
+            *offset += content.len() as u64;
 
-            return content;
+            return Ok(content);
         }
     };
 }
 
+// Decode UTF-8, each byte of an invalid sequence replaced by `?` so the
+// text keeps the length of the bytes it comes from and offsets stay right.
+// A character cut at the end is left out, it is read again with its line.
+// Return the text and the count of replaced bytes.
+fn decode_lossy(mut bytes: &[u8]) -> (String, usize) {
+    let mut text = String::with_capacity(bytes.len());
+    let mut invalid_size: usize = 0;
+
+    loop {
+        match str::from_utf8(bytes) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return (text, invalid_size);
+            },
+            Err(why) => {
+                let valid_size: usize = why.valid_up_to();
+                text.push_str(str::from_utf8(&bytes[..valid_size]).unwrap());
+
+                let error_size: usize = match why.error_len() {
+                    Some(error_size) => error_size,
+                    None => return (text, invalid_size),
+                };
+
+                for _ in 0..error_size {
+                    text.push('?');
+                }
+                invalid_size += error_size;
+                bytes = &bytes[valid_size + error_size..];
+            },
+        }
+    }
+}
+
 struct LogEventResponse {
-    token: Option<String>
+    token: String,
+    /// Events of the batch the service has not stored
+    rejected: Vec<RejectedEvent>,
 }
-struct LogEventError {
-    token: Option<String>
+enum LogEventError {
+    /// The sequence token has been refused, holds the expected one when given
+    InvalidSequenceToken(Option<String>),
+    Failed(Error),
 }
 
-// We should / MUST use state file to persist the position with the latest
-// sequence_token to avoid duplication log or data loss if agent restart
-// And also maybe replace message by vector to reduce the HTTP call and
-// increase performance on high rate log stream.
-fn put_log_events(
-    message: &String,
-    log_group_name: &String,
-    log_stream_name: &String,
-    token: Option<String>,
-    client: &Box<CloudWatchLogs>
-) -> Result<LogEventResponse, LogEventError> {
+impl From<Error> for LogEventError {
+    fn from(why: Error) -> LogEventError {
+        return LogEventError::Failed(why);
+    }
+}
+
+/// Build one event per message
+///
+/// The timestamp of each event is parsed from the beginning of the message
+/// using `datetime_format`. Messages without timestamp get one according
+/// to the `timestamp_fallback` policy, `last_timestamp` keeps track of
+/// the previous event between calls. Messages too large for a single
+/// event are truncated, the entry keeps the part cut off for the dead-letter file.
+fn build_events(
+    messages: &Vec<(String, u64)>,
+    log_file: &ConfigLogFile,
+    last_timestamp: &mut Option<i64>
+) -> Vec<Entry> {
     let utc: DateTime<Utc> = Utc::now();
-    let tz_milliseconds: i64 = utc.timestamp() * 1000;
-    let mut events: Vec<InputLogEvent> = Vec::new();
+    let now_milliseconds: i64 = utc.timestamp() * 1000;
+    let mut events: Vec<Entry> = Vec::new();
 
-    for line in message.lines() {
+    for &(ref line, end_offset) in messages {
         if line.is_empty() {
             continue;
         }
+
+        let line_timestamp: i64 = match timestamp::parse(
+            line,
+            &log_file.datetime_format,
+            &log_file.time_zone,
+            utc
+        ) {
+            Some(parsed) => parsed,
+            None => match (log_file.timestamp_fallback, last_timestamp.to_owned()) {
+                (TimestampFallback::Previous, Some(previous)) => previous,
+                _ => now_milliseconds,
+            },
+        };
+        *last_timestamp = Some(line_timestamp);
+
+        let mut message_size: usize = cmp::min(line.len(), batch::MAX_EVENT_SIZE - batch::EVENT_OVERHEAD);
+        while !line.is_char_boundary(message_size) {
+            message_size -= 1;
+        }
+        let truncated: Option<String> = if message_size < line.len() {
+            println!("WARNING: event of {} bytes in {} truncated to {} bytes before offset {}, the rest goes to the dead-letter file",
+                     line.len(), log_file.file, message_size, end_offset);
+            Some(line[message_size..].to_string())
+        } else {
+            None
+        };
+
         let inline_event: InputLogEvent = InputLogEvent {
-            message: line.to_string(),
-            timestamp: tz_milliseconds, // TODO must be defined by the beginning of string parse using log_file.datetime_format
+            message: line[..message_size].to_string(),
+            timestamp: line_timestamp,
         };
-        events.push(inline_event);
+        events.push(Entry { event: inline_event, end_offset, truncated });
     }
 
-    if events.is_empty() {
-        return Ok(LogEventResponse { token });
-    }
+    return events;
+}
 
+// We should / MUST use state file to persist the position with the latest
+// sequence_token to avoid duplication log or data loss if agent restart
+fn put_log_events(
+    events: Vec<InputLogEvent>,
+    log_group_name: &String,
+    log_stream_name: &String,
+    token: Option<String>,
+    client: &Box<CloudWatchLogs>,
+    retry: RetryPolicy
+) -> Result<LogEventResponse, LogEventError> {
     let log_event_request: PutLogEventsRequest = PutLogEventsRequest {
         log_events: events,
         log_group_name: log_group_name.to_owned(),
@@ -280,32 +645,195 @@ fn put_log_events(
         sequence_token: token,
     };
 
-    let result_log = client.put_log_events(&log_event_request);
+    // Transient failures are sent again after a growing delay
+    let mut attempt: u32 = 1;
+    let result_log = loop {
+        let result = client.put_log_events(&log_event_request);
+
+        let retryable: bool = match result {
+            Err(ref why) => attempt < retry.max_attempts && retry::is_retryable(why),
+            Ok(_) => false,
+        };
+        if !retryable {
+            break result;
+        }
+
+        let delay: Duration = retry.delay(attempt);
+        if let Err(ref why) = result {
+            println!("WARNING: put log events to {}/{} failed on attempt {}/{}, retry in {} ms : {}",
+                     log_group_name, log_stream_name, attempt, retry.max_attempts,
+                     delay.as_secs() * 1000 + (delay.subsec_nanos() / 1000000) as u64, why);
+        }
+
+        sleep(delay);
+        attempt += 1;
+    };
 
     return match result_log {
         Err(why) => {
             match why {
-                PutLogEventsError::InvalidSequenceToken(cause) => {
-                    let pat = "expected sequenceToken is: ";
-                    let token = match cause.find(pat) {
-                        None => None,
-                        Some(position) => {
-                            Some(cause.get(pat.len() + position..).unwrap().to_string())
+                PutLogEventsError::InvalidSequenceToken(message) => {
+                    println!("WARNING: sequence token refused by {}/{} : {}", log_group_name, log_stream_name, message);
+
+                    // Without a token to recover, the next attempt is refused with the expected one
+                    let expected_token: Option<String> = match token_from_message(&message) {
+                        Some(token) => Some(token),
+                        None => match upload_sequence_token(log_group_name, log_stream_name, client, retry) {
+                            Ok(token) => token,
+                            Err(why) => {
+                                println!("WARNING: {}, send again without sequence token", why);
+                                None
+                            },
                         },
                     };
-                    Err(LogEventError { token })
+                    Err(LogEventError::InvalidSequenceToken(expected_token))
                 },
-                _ => {
-                    // Err(LogEventError { parent_error: why, token: None })
-                    panic!("Put Log event have failed: {}", why.description());
+                PutLogEventsError::DataAlreadyAccepted(message) => {
+                    // A previous attempt has been stored but its answer was lost, the
+                    // batch is acknowledged here so the offset moves past it only once.
+                    println!("WARNING: batch already accepted by {}/{} : {}", log_group_name, log_stream_name, message);
+
+                    let next_token: Result<Option<String>, Error> = match token_from_message(&message) {
+                        Some(token) => Ok(Some(token)),
+                        None => upload_sequence_token(log_group_name, log_stream_name, client, retry),
+                    };
+                    match next_token {
+                        Ok(Some(token)) => Ok(LogEventResponse { token, rejected: Vec::new() }),
+                        Ok(None) => Err(LogEventError::Failed(Error::Aws(format!(
+                            "No sequence token for {}/{} after an accepted batch",
+                            log_group_name,
+                            log_stream_name
+                        )))),
+                        Err(why) => Err(LogEventError::Failed(why)),
+                    }
                 },
+                _ => Err(LogEventError::Failed(Error::Aws(format!(
+                    "Put log events to {}/{} have failed on attempt {}/{}: {}",
+                    log_group_name,
+                    log_stream_name,
+                    attempt,
+                    retry.max_attempts,
+                    why
+                )))),
             }
         },
         Ok(response) => {
-            let token: String = response.next_sequence_token.unwrap();
-            /*println!("Put Log with success time:{}", tz_milliseconds);
-            println!("Next seq token :{}", token);*/
-            Ok(LogEventResponse { token: Some(token) })
+            let rejected: Vec<RejectedEvent> = match response.rejected_log_events_info {
+                Some(ref info) => rejected::collect(info, log_event_request.log_events),
+                None => Vec::new(),
+            };
+
+            match response.next_sequence_token {
+                Some(token) => Ok(LogEventResponse { token, rejected }),
+                None => Err(LogEventError::Failed(Error::Aws(format!(
+                    "No sequence token returned for {}/{}",
+                    log_group_name,
+                    log_stream_name
+                )))),
+            }
         },
     }
 }
+
+// Sequence token written at the end of an error message, such as
+// "The next expected sequenceToken is: 4960..." or "The next batch can be
+// sent with sequenceToken: 4960...". None when it cannot be found, or when
+// it is `null` as for a stream which never received events.
+fn token_from_message(message: &str) -> Option<String> {
+    let position: usize = message.rfind("sequenceToken")?;
+    let rest: &str = &message[position..];
+    let token: &str = rest[rest.find(':')? + 1..].trim();
+
+    if token.is_empty() || "null" == token || !token.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    return Some(token.to_string());
+}
+
+/// Sequence token expected by the stream, as returned by DescribeLogStreams
+///
+/// None when the stream has never received events. Transient failures are
+/// sent again following `retry`, as PutLogEvents ones, up to `max_attempts`.
+fn upload_sequence_token(
+    log_group_name: &String,
+    log_stream_name: &String,
+    client: &Box<CloudWatchLogs>,
+    retry: RetryPolicy
+) -> Result<Option<String>, Error> {
+    let mut next_token: Option<String> = None;
+
+    loop {
+        let request: DescribeLogStreamsRequest = DescribeLogStreamsRequest {
+            log_group_name: log_group_name.to_owned(),
+            log_stream_name_prefix: Some(log_stream_name.to_owned()),
+            next_token,
+            ..Default::default()
+        };
+
+        let mut attempt: u32 = 1;
+        let response = loop {
+            match client.describe_log_streams(&request) {
+                Ok(response) => break response,
+                Err(ref why) if attempt < retry.max_attempts && retry::is_describe_retryable(why) => {
+                    let delay: Duration = retry.delay(attempt);
+                    println!("WARNING: describe log stream {}/{} failed on attempt {}/{}, retry in {} ms : {}",
+                             log_group_name, log_stream_name, attempt, retry.max_attempts,
+                             delay.as_secs() * 1000 + (delay.subsec_nanos() / 1000000) as u64, why);
+
+                    sleep(delay);
+                    attempt += 1;
+                },
+                Err(why) => return Err(Error::Aws(format!(
+                    "Describe log stream {}/{} have failed on attempt {}/{}: {}",
+                    log_group_name,
+                    log_stream_name,
+                    attempt,
+                    retry.max_attempts,
+                    why
+                ))),
+            }
+        };
+
+        // The prefix may match other streams, such as app-1 and app-10
+        for log_stream in response.log_streams.unwrap_or_default() {
+            if Some(log_stream_name) == log_stream.log_stream_name.as_ref() {
+                return Ok(log_stream.upload_sequence_token);
+            }
+        }
+
+        next_token = response.next_token;
+        if next_token.is_none() {
+            return Err(Error::Aws(format!("Log stream {}/{} not found", log_group_name, log_stream_name)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_lossy;
+
+    #[test]
+    fn valid_text_is_kept() {
+        assert_eq!(("caf\u{e9} ok".to_string(), 0), decode_lossy("caf\u{e9} ok".as_bytes()));
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced_one_for_one() {
+        let bytes: &[u8] = b"latin1 caf\xe9 and \xff\xfe end";
+        let (text, invalid_size) = decode_lossy(bytes);
+
+        assert_eq!("latin1 caf? and ?? end", text);
+        assert_eq!(3, invalid_size);
+        assert_eq!(bytes.len(), text.len());
+    }
+
+    #[test]
+    fn character_cut_at_the_end_is_left_out() {
+        let bytes: &[u8] = "bad \u{fffd}".as_bytes();
+        let (text, invalid_size) = decode_lossy(&bytes[..bytes.len() - 1]);
+
+        assert_eq!("bad ", text);
+        assert_eq!(0, invalid_size);
+    }
+}
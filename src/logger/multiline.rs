@@ -0,0 +1,339 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use chrono::{Utc};
+use regex::{self, Regex};
+use config::configuration::{LogTimeZone, MultiLinePreset};
+use logger::timestamp;
+
+/// Value of `multi_line_start_pattern` asking to use the `datetime_format`
+const DATETIME_FORMAT_PATTERN: &'static str = "{datetime_format}";
+
+const JAVA_CONTINUATION: &'static str =
+    r"^(\s+at\s|\s+\.\.\.\s\d+\s(more|common frames omitted)|\s*Caused by:\s|\s+Suppressed:\s|([\w$]+\.)+[\w$]*(Exception|Error|Throwable)(:\s|$))";
+const PYTHON_CONTINUATION: &'static str =
+    r"^(\s|Traceback \(most recent call last\):|During handling of the above exception|The above exception was the direct cause)";
+const GO_PANIC_CONTINUATION: &'static str =
+    r"^(\s|goroutine \d+ \[|created by\s|\[signal\s|exit status\s|[\w./\-*()]+\(.*\)$)";
+const RUBY_CONTINUATION: &'static str = r"^\s+(from\s)?\S+:\d+:in\s";
+const DOTNET_CONTINUATION: &'static str = r"^(\s+at\s|\s*---\s|\s*--->\s)";
+const INDENTED_CONTINUATION: &'static str = r"^\s";
+
+const PYTHON_TRACEBACK: &'static str = "Traceback (most recent call last):";
+
+/// Rule telling if a line is the first one of an event
+pub enum StartPattern {
+    Regex(Regex),
+    /// A line starting by a timestamp in `datetime_format` starts an event
+    DatetimeFormat(String, LogTimeZone),
+    /// A line matching the regex of the preset continues the current event
+    Preset(MultiLinePreset, Regex),
+}
+
+impl StartPattern {
+    /// Build the rule from the `multi_line_start_pattern` configuration
+    ///
+    /// Same meaning as the Python agent: either a regular expression or
+    /// `{datetime_format}` to reuse the datetime format of the log file.
+    pub fn new(
+        pattern: &str,
+        datetime_format: &str,
+        time_zone: &LogTimeZone
+    ) -> Result<StartPattern, regex::Error> {
+        if DATETIME_FORMAT_PATTERN == pattern {
+            return Ok(StartPattern::DatetimeFormat(
+                datetime_format.to_owned(),
+                time_zone.to_owned()
+            ));
+        }
+
+        return Regex::new(pattern).map(StartPattern::Regex);
+    }
+
+    /// Build the rule of a `multi_line_preset`
+    pub fn from_preset(preset: MultiLinePreset) -> StartPattern {
+        let continuation: &'static str = match preset {
+            MultiLinePreset::Java => JAVA_CONTINUATION,
+            MultiLinePreset::Python => PYTHON_CONTINUATION,
+            MultiLinePreset::GoPanic => GO_PANIC_CONTINUATION,
+            MultiLinePreset::Ruby => RUBY_CONTINUATION,
+            MultiLinePreset::Dotnet => DOTNET_CONTINUATION,
+            MultiLinePreset::Indented => INDENTED_CONTINUATION,
+        };
+
+        return StartPattern::Preset(preset, Regex::new(continuation).unwrap());
+    }
+
+    /// Tell if the line starts a new event
+    ///
+    /// `current` is the message of the event being built, if any.
+    pub fn is_start(&self, line: &str, current: Option<&str>) -> bool {
+        return match *self {
+            StartPattern::Regex(ref regex) => regex.is_match(line),
+            StartPattern::DatetimeFormat(ref format, ref time_zone) => {
+                timestamp::parse(line, format, time_zone, Utc::now()).is_some()
+            },
+            StartPattern::Preset(preset, ref continuation) => match current {
+                None => true,
+                Some(current) => !is_continuation(preset, continuation, line, current),
+            },
+        };
+    }
+}
+
+fn is_continuation(
+    preset: MultiLinePreset,
+    continuation: &Regex,
+    line: &str,
+    current: &str
+) -> bool {
+    match preset {
+        // Frames only make sense after the panic message, otherwise
+        // any log line looking like a function call would be merged
+        MultiLinePreset::GoPanic => {
+            if !current.starts_with("panic: ") && !current.starts_with("fatal error: ") {
+                return false;
+            }
+        },
+        // The exception closing a traceback is not indented,
+        // it follows the last indented line of the frames
+        MultiLinePreset::Python => {
+            let previous: &str = current.rsplit('\n').next().unwrap_or("");
+            if current.contains(PYTHON_TRACEBACK)
+                && previous.starts_with(char::is_whitespace)
+                && is_python_exception(line) {
+                return true;
+            }
+        },
+        _ => {},
+    }
+
+    return continuation.is_match(line);
+}
+
+// Match `ValueError`, `ValueError: message` or `package.module.Error: message`
+fn is_python_exception(line: &str) -> bool {
+    let name: &str = line.split(':').next().unwrap_or("");
+
+    return !name.is_empty()
+        && name.chars().all(|c| c.is_alphanumeric() || '_' == c || '.' == c);
+}
+
+/// Lines of an event and its position in the content it comes from
+pub struct Group {
+    pub message: String,
+    pub start: usize,
+}
+
+/// Group the lines of content into events
+///
+/// A line matching the start pattern opens a new event and the following
+/// lines which do not match are appended to it. Lines before the first
+/// match form an event on their own. Empty lines are dropped.
+pub fn group(content: &str, pattern: &StartPattern) -> Vec<Group> {
+    let mut groups: Vec<Group> = Vec::new();
+    let mut position: usize = 0;
+
+    for raw_line in content.split('\n') {
+        let start: usize = position;
+        position += raw_line.len() + 1;
+
+        let line: &str = raw_line.trim_right_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+
+        if groups.is_empty()
+            || pattern.is_start(line, groups.last().map(|current| current.message.as_str())) {
+            groups.push(Group { message: line.to_string(), start });
+            continue;
+        }
+
+        if let Some(current) = groups.last_mut() {
+            current.message.push('\n');
+            current.message.push_str(line);
+        }
+    }
+
+    return groups;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JAVA_LOG: &'static str = include_str!("../../tests/fixtures/multiline/java.log");
+    const PYTHON_LOG: &'static str = include_str!("../../tests/fixtures/multiline/python.log");
+    const GO_PANIC_LOG: &'static str = include_str!("../../tests/fixtures/multiline/go_panic.log");
+    const RUBY_LOG: &'static str = include_str!("../../tests/fixtures/multiline/ruby.log");
+    const DOTNET_LOG: &'static str = include_str!("../../tests/fixtures/multiline/dotnet.log");
+    const INDENTED_LOG: &'static str = include_str!("../../tests/fixtures/multiline/indented.log");
+
+    fn messages(content: &str, preset: MultiLinePreset) -> Vec<String> {
+        return group(content, &StartPattern::from_preset(preset))
+            .into_iter()
+            .map(|group| group.message)
+            .collect();
+    }
+
+    #[test]
+    fn java_groups_stack_trace_with_causes() {
+        let messages = messages(JAVA_LOG, MultiLinePreset::Java);
+
+        assert_eq!(3, messages.len());
+        assert_eq!("2018-03-01 10:00:00 INFO Starting service", messages[0]);
+        assert_eq!(
+            "2018-03-01 10:00:01 ERROR Request failed\n\
+             java.lang.IllegalStateException: boom\n\
+             \tat com.example.Service.handle(Service.java:42)\n\
+             \tat com.example.Server.run(Server.java:17)\n\
+             Caused by: java.io.IOException: stream closed\n\
+             \tat com.example.Io.read(Io.java:9)\n\
+             \t... 2 more",
+            messages[1]
+        );
+        assert_eq!("2018-03-01 10:00:02 INFO Recovered", messages[2]);
+    }
+
+    #[test]
+    fn python_groups_chained_tracebacks() {
+        let messages = messages(PYTHON_LOG, MultiLinePreset::Python);
+
+        assert_eq!(2, messages.len());
+        assert!(messages[0].starts_with("2018-03-01 10:00:00 ERROR Cannot load settings\nTraceback"));
+        assert!(messages[0].contains("\nValueError: bad value\nDuring handling of the above exception"));
+        assert!(messages[0].ends_with("\n    raise SettingsError(path)\nsettings.SettingsError: /etc/app.conf"));
+        assert_eq!("2018-03-01 10:00:01 INFO Using defaults", messages[1]);
+    }
+
+    #[test]
+    fn python_exception_line_needs_traceback() {
+        let messages = messages("Starting\n  indented detail\nValueError: not in a traceback\n", MultiLinePreset::Python);
+
+        assert_eq!(vec!["Starting\n  indented detail", "ValueError: not in a traceback"], messages);
+    }
+
+    #[test]
+    fn go_panic_groups_goroutines() {
+        let messages = messages(GO_PANIC_LOG, MultiLinePreset::GoPanic);
+
+        assert_eq!(3, messages.len());
+        assert_eq!("2018/03/01 10:00:00 serving on :8080", messages[0]);
+        assert!(messages[1].starts_with("panic: runtime error"));
+        assert!(messages[1].contains("\ngoroutine 1 [running]:\nmain.handler(0x0)\n"));
+        assert!(messages[1].ends_with("\n\t/app/main.go:20 +0x25\nexit status 2"));
+        assert_eq!("2018/03/01 10:00:01 restarted", messages[2]);
+    }
+
+    #[test]
+    fn go_panic_frames_need_panic() {
+        let messages = messages("main.handler(0x0)\nmain.main()\n", MultiLinePreset::GoPanic);
+
+        assert_eq!(vec!["main.handler(0x0)", "main.main()"], messages);
+    }
+
+    #[test]
+    fn ruby_groups_backtrace() {
+        let messages = messages(RUBY_LOG, MultiLinePreset::Ruby);
+
+        assert_eq!(3, messages.len());
+        assert!(messages[1].starts_with("app.rb:10:in `divide': divided by 0 (ZeroDivisionError)\n\tfrom app.rb:14"));
+        assert!(messages[1].ends_with("run.rb:3:in `call'"));
+        assert_eq!("I, [2018-03-01T10:00:01] INFO -- : done", messages[2]);
+    }
+
+    #[test]
+    fn dotnet_groups_inner_exceptions() {
+        let messages = messages(DOTNET_LOG, MultiLinePreset::Dotnet);
+
+        assert_eq!(3, messages.len());
+        assert_eq!("2018-03-01 10:00:00 ERROR Unhandled exception", messages[0]);
+        assert_eq!(
+            "System.InvalidOperationException: Operation failed ---> System.IO.IOException: Disk full\n   \
+             at App.Storage.Write() in /src/Storage.cs:line 21\n   \
+             --- End of inner exception stack trace ---\n   \
+             at App.Program.Main() in /src/Program.cs:line 8",
+            messages[1]
+        );
+        assert_eq!("2018-03-01 10:00:01 INFO Restarted", messages[2]);
+    }
+
+    #[test]
+    fn indented_groups_following_lines() {
+        let messages = messages(INDENTED_LOG, MultiLinePreset::Indented);
+
+        assert_eq!(vec![
+            "2018-03-01 10:00:00 Loaded configuration:\n  listen: 0.0.0.0:8080\n  workers: 4",
+            "2018-03-01 10:00:01 Ready",
+        ], messages);
+    }
+
+    #[test]
+    fn groups_start_at_their_first_line() {
+        let groups = group(JAVA_LOG, &StartPattern::from_preset(MultiLinePreset::Java));
+
+        for current in &groups {
+            let first_line: &str = current.message.split('\n').next().unwrap();
+            assert!(JAVA_LOG[current.start..].starts_with(first_line));
+        }
+        assert_eq!(0, groups[0].start);
+    }
+
+    #[test]
+    fn pending_tail_is_completed_by_next_chunk() {
+        let pattern = StartPattern::from_preset(MultiLinePreset::Java);
+        let cut: usize = JAVA_LOG.find("Caused by:").unwrap();
+
+        // The chunk ends in the middle of the trace, the last group is the
+        // unfinished event the consumer keeps in the file
+        let groups = group(&JAVA_LOG[..cut], &pattern);
+        let tail: &Group = groups.last().unwrap();
+        assert_eq!(2, groups.len());
+        assert_eq!(JAVA_LOG.find("2018-03-01 10:00:01").unwrap(), tail.start);
+        assert!(tail.message.ends_with("Server.java:17)"));
+
+        // Read again from the tail, the event is complete once the next starts
+        let groups = group(&JAVA_LOG[tail.start..], &pattern);
+        assert_eq!(2, groups.len());
+        assert!(groups[0].message.ends_with("\t... 2 more"));
+        assert_eq!("2018-03-01 10:00:02 INFO Recovered", groups[1].message);
+    }
+
+    #[test]
+    fn datetime_format_pattern_starts_on_timestamps() {
+        let pattern = StartPattern::new("{datetime_format}", "%Y-%m-%d %H:%M:%S", &LogTimeZone::Utc).unwrap();
+        let groups = group(INDENTED_LOG, &pattern);
+
+        assert_eq!(2, groups.len());
+        assert!(groups[0].message.ends_with("workers: 4"));
+    }
+}
@@ -0,0 +1,212 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+use rusoto_logs::{InputLogEvent, RejectedLogEventsInfo};
+use error::Error;
+
+/// Why CloudWatch Logs refused to store an event, or a part of it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Reason {
+    /// Older than 14 days
+    TooOld,
+    /// Older than the retention period of the log group
+    Expired,
+    /// More than 2 hours in the future
+    TooNew,
+    /// End of a message too large for a single event
+    Truncated,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reason::TooOld => write!(f, "too_old"),
+            Reason::Expired => write!(f, "expired"),
+            Reason::TooNew => write!(f, "too_new"),
+            Reason::Truncated => write!(f, "truncated"),
+        }
+    }
+}
+
+pub struct RejectedEvent {
+    pub reason: Reason,
+    pub event: InputLogEvent,
+}
+
+/// Number of events refused since the logfile is watched
+#[derive(Default)]
+pub struct Counters {
+    pub too_old: u64,
+    pub expired: u64,
+    pub too_new: u64,
+    pub truncated: u64,
+}
+
+impl Counters {
+    pub fn add(&mut self, rejected: &[RejectedEvent]) {
+        for rejected_event in rejected {
+            match rejected_event.reason {
+                Reason::TooOld => self.too_old += 1,
+                Reason::Expired => self.expired += 1,
+                Reason::TooNew => self.too_new += 1,
+                Reason::Truncated => self.truncated += 1,
+            }
+        }
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} too old, {} expired, {} too new, {} truncated",
+               self.too_old, self.expired, self.too_new, self.truncated)
+    }
+}
+
+/// Pick the events of a batch the service has not stored
+///
+/// Events before the too old or expired end index, and from the too new
+/// start index, have been refused. An event is given the first reason
+/// matching it.
+pub fn collect(info: &RejectedLogEventsInfo, events: Vec<InputLogEvent>) -> Vec<RejectedEvent> {
+    let too_old_end: i64 = info.too_old_log_event_end_index.unwrap_or(0);
+    let expired_end: i64 = info.expired_log_event_end_index.unwrap_or(0);
+    let too_new_start: i64 = info.too_new_log_event_start_index.unwrap_or(i64::max_value());
+
+    return events.into_iter()
+        .enumerate()
+        .filter_map(|(index, event)| {
+            let index: i64 = index as i64;
+            let reason: Reason = if index < too_old_end {
+                Reason::TooOld
+            } else if index < expired_end {
+                Reason::Expired
+            } else if index >= too_new_start {
+                Reason::TooNew
+            } else {
+                return None;
+            };
+
+            Some(RejectedEvent { reason, event })
+        })
+        .collect();
+}
+
+/// Append the rejected events to the dead-letter file, one JSON object per line
+///
+/// The directory of the file is created when missing.
+pub fn append(path: &String, rejected: &[RejectedEvent]) -> Result<(), Error> {
+    if let Some(directory) = Path::new(path).parent() {
+        if let Err(why) = fs::create_dir_all(directory) {
+            return Err(Error::Io(format!("Cannot create directory of dead-letter file {}", path), why));
+        }
+    }
+
+    let file = match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(why) => return Err(Error::Io(format!("Cannot open dead-letter file {}", path), why)),
+    };
+
+    let mut writer = BufWriter::new(file);
+    for rejected_event in rejected {
+        let line = json!({
+            "reason": rejected_event.reason.to_string(),
+            "timestamp": rejected_event.event.timestamp,
+            "message": rejected_event.event.message,
+        });
+
+        if let Err(why) = writeln!(writer, "{}", line) {
+            return Err(Error::Io(format!("Cannot write dead-letter file {}", path), why));
+        }
+    }
+
+    return writer.flush()
+        .map_err(|why| Error::Io(format!("Cannot write dead-letter file {}", path), why));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use rusoto_logs::{InputLogEvent, RejectedLogEventsInfo};
+    use super::{append, collect, Reason};
+
+    fn events(count: i64) -> Vec<InputLogEvent> {
+        return (0..count).map(|index| InputLogEvent {
+            message: format!("event {}", index),
+            timestamp: index,
+        }).collect();
+    }
+
+    #[test]
+    fn events_outside_the_indexes_are_collected() {
+        let info = RejectedLogEventsInfo {
+            too_old_log_event_end_index: Some(1),
+            expired_log_event_end_index: Some(2),
+            too_new_log_event_start_index: Some(4),
+        };
+
+        let reasons: Vec<(Reason, String)> = collect(&info, events(5)).into_iter()
+            .map(|rejected_event| (rejected_event.reason, rejected_event.event.message))
+            .collect();
+
+        assert_eq!(vec![
+            (Reason::TooOld, "event 0".to_string()),
+            (Reason::Expired, "event 1".to_string()),
+            (Reason::TooNew, "event 4".to_string()),
+        ], reasons);
+    }
+
+    #[test]
+    fn append_creates_the_directory() {
+        let directory: String = format!("{}/awatchlog-dead-letter-{}", env::temp_dir().display(), ::std::process::id());
+        let path: String = format!("{}/nested/app.log.rejected", directory);
+        let info = RejectedLogEventsInfo {
+            too_old_log_event_end_index: Some(2),
+            ..Default::default()
+        };
+
+        append(&path, &collect(&info, events(3))).unwrap();
+
+        let lines: Vec<String> = fs::read_to_string(&path).unwrap().lines().map(String::from).collect();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].contains(r#""reason":"too_old""#), "{}", lines[0]);
+        assert!(lines[1].contains(r#""message":"event 1""#), "{}", lines[1]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::cmp;
+use std::time::Duration;
+use ring::rand::{SecureRandom, SystemRandom};
+use rusoto_logs::{DescribeLogStreamsError, PutLogEventsError};
+
+// Delay before the first retry, doubled on each attempt
+const BASE_DELAY_MILLISECONDS: u64 = 200;
+
+// Error types AWS may answer with when it is overloaded or momentarily broken
+const RETRYABLE_ERROR_TYPES: &'static [&'static str] = &[
+    "ThrottlingException",
+    "InternalFailure",
+    "ServiceUnavailable",
+    "RequestTimeout",
+    "ExpiredTokenException",
+];
+
+/// How often and how long to retry a request which failed transiently
+///
+/// Once `max_attempts` have failed the error is returned, the watcher of the
+/// file then starts again from the saved offset so no event is lost.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Number of attempts, the first one included
+    pub max_attempts: u32,
+    /// Upper bound of the delay between two attempts
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay to wait after the given failed attempt, starting at 1
+    ///
+    /// The delay doubles on each attempt up to `max_delay`, and a random
+    /// part of up to half of it keeps the agents of a fleet from retrying
+    /// all at the same time.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let max_delay: u64 = self.max_delay.as_secs() * 1000 + (self.max_delay.subsec_nanos() / 1000000) as u64;
+        let exponent: u32 = cmp::min(attempt.saturating_sub(1), 32);
+        let ceiling: u64 = cmp::min(max_delay, BASE_DELAY_MILLISECONDS.saturating_mul(1 << exponent));
+        let jitter: u64 = match ceiling / 2 {
+            0 => 0,
+            half => random() % (half + 1),
+        };
+
+        return Duration::from_millis(ceiling - jitter);
+    }
+}
+
+/// Tell if a failed PutLogEvents may succeed when sent again as is
+///
+/// Sequence token and already accepted errors are not covered here,
+/// they need another request rather than the same one. Credentials which
+/// could not be fetched, or refreshed, are asked for again on the next attempt.
+pub fn is_retryable(error: &PutLogEventsError) -> bool {
+    return match *error {
+        PutLogEventsError::ServiceUnavailable(_) => true,
+        PutLogEventsError::HttpDispatch(_) => true,
+        PutLogEventsError::Credentials(_) => true,
+        PutLogEventsError::Unknown(ref body) => is_retryable_body(body),
+        _ => false,
+    };
+}
+
+/// Tell if a failed DescribeLogStreams may succeed when sent again as is
+pub fn is_describe_retryable(error: &DescribeLogStreamsError) -> bool {
+    return match *error {
+        DescribeLogStreamsError::ServiceUnavailable(_) => true,
+        DescribeLogStreamsError::HttpDispatch(_) => true,
+        DescribeLogStreamsError::Credentials(_) => true,
+        DescribeLogStreamsError::Unknown(ref body) => is_retryable_body(body),
+        _ => false,
+    };
+}
+
+// Raw body of the response, not JSON when answered by a proxy or a load balancer
+fn is_retryable_body(body: &str) -> bool {
+    return !body.trim_left().starts_with('{')
+        || RETRYABLE_ERROR_TYPES.iter().any(|error_type| body.contains(error_type));
+}
+
+fn random() -> u64 {
+    let mut bytes = [0u8; 8];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return 0;
+    }
+
+    return bytes.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use rusoto_credential::CredentialsError;
+    use rusoto_logs::{DescribeLogStreamsError, PutLogEventsError};
+    use super::{is_describe_retryable, is_retryable, RetryPolicy};
+
+    fn policy() -> RetryPolicy {
+        return RetryPolicy {
+            max_attempts: 4,
+            max_delay: Duration::from_millis(1000),
+        };
+    }
+
+    #[test]
+    fn delay_grows_up_to_max_delay() {
+        for attempt in 1..4 {
+            let ceiling: u64 = 200 << (attempt - 1);
+            let delay: Duration = policy().delay(attempt);
+            assert!(delay <= Duration::from_millis(ceiling), "attempt {} waits {:?}", attempt, delay);
+            assert!(delay >= Duration::from_millis(ceiling / 2), "attempt {} waits {:?}", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn delay_is_capped_at_max_delay() {
+        for attempt in &[4, 5, 100, u32::max_value()] {
+            let delay: Duration = policy().delay(*attempt);
+            assert!(delay <= Duration::from_millis(1000), "attempt {} waits {:?}", attempt, delay);
+            assert!(delay >= Duration::from_millis(500), "attempt {} waits {:?}", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        assert!(is_retryable(&PutLogEventsError::ServiceUnavailable("unavailable".to_string())));
+        assert!(is_retryable(&PutLogEventsError::Credentials(CredentialsError::new("role refresh timed out"))));
+        assert!(is_retryable(&PutLogEventsError::Unknown(
+            r#"{"__type":"ThrottlingException","message":"Rate exceeded"}"#.to_string()
+        )));
+        assert!(is_retryable(&PutLogEventsError::Unknown("<html>502 Bad Gateway</html>".to_string())));
+
+        assert!(!is_retryable(&PutLogEventsError::Unknown(
+            r#"{"__type":"AccessDeniedException","message":"denied"}"#.to_string()
+        )));
+        assert!(!is_retryable(&PutLogEventsError::Validation("invalid".to_string())));
+        assert!(!is_retryable(&PutLogEventsError::InvalidParameter("too large".to_string())));
+    }
+    #[test]
+    fn only_transient_describe_errors_are_retried() {
+        assert!(is_describe_retryable(&DescribeLogStreamsError::ServiceUnavailable("unavailable".to_string())));
+        assert!(is_describe_retryable(&DescribeLogStreamsError::Unknown(
+            r#"{"__type":"ThrottlingException","message":"Rate exceeded"}"#.to_string()
+        )));
+
+        assert!(is_describe_retryable(&DescribeLogStreamsError::Credentials(CredentialsError::new("metadata timed out"))));
+
+        assert!(!is_describe_retryable(&DescribeLogStreamsError::ResourceNotFound("no group".to_string())));
+    }
+}
@@ -0,0 +1,163 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::FileExt;
+use flate2::read::MultiGzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// Compression of a log file, guessed from its extension
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_path(path: &str) -> Compression {
+        if path.ends_with(".gz") {
+            return Compression::Gzip;
+        }
+
+        if path.ends_with(".zst") {
+            return Compression::Zstd;
+        }
+
+        return Compression::None;
+    }
+}
+
+/// Log file read at an offset whatever its compression
+///
+/// For compressed files the offset is counted in the decompressed stream.
+/// The stream can only be read forward, so the bytes from the last offset
+/// requested are kept in a window to read them again when a batch has to
+/// be sent again, an older offset reopens the stream from start.
+pub struct LogSource {
+    file: File,
+    compression: Compression,
+    decoder: Option<Box<Read>>,
+    window: Vec<u8>,
+    window_start: u64,
+}
+
+impl LogSource {
+    pub fn new(file: File, path: &str) -> LogSource {
+        return LogSource {
+            file,
+            compression: Compression::from_path(path),
+            decoder: None,
+            window: Vec::new(),
+            window_start: 0,
+        };
+    }
+
+    pub fn file(&self) -> &File {
+        return &self.file;
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        return Compression::None != self.compression;
+    }
+
+    /// Read up to `buffer.len()` bytes at `offset`, return 0 at end of file
+    pub fn read_at(&mut self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        if !self.is_compressed() {
+            return self.file.read_at(buffer, offset);
+        }
+
+        if self.decoder.is_none() || offset < self.window_start {
+            self.reset()?;
+        }
+
+        // Forget what is before the offset, reading and dropping
+        // the decompressed bytes when the window does not reach it
+        let drained: usize = ((offset - self.window_start) as usize).min(self.window.len());
+        self.window.drain(..drained);
+        self.window_start += drained as u64;
+
+        // The window is empty here, its memory is used to drop the bytes
+        while self.window_start < offset {
+            let skip_size: usize = ((offset - self.window_start) as usize).min(buffer.len());
+            self.window.resize(skip_size, 0);
+            let n: usize = decode(&mut self.decoder, &mut self.window)?;
+            self.window.clear();
+            if 0 == n {
+                return Ok(0);
+            }
+            self.window_start += n as u64;
+        }
+
+        // Fill the window up to the size requested, decoding in place
+        // so its memory is kept from one read to the next
+        while self.window.len() < buffer.len() {
+            let filled: usize = self.window.len();
+            self.window.resize(buffer.len(), 0);
+            let n: usize = decode(&mut self.decoder, &mut self.window[filled..])?;
+            self.window.truncate(filled + n);
+            if 0 == n {
+                break;
+            }
+        }
+
+        let size: usize = self.window.len().min(buffer.len());
+        buffer[..size].copy_from_slice(&self.window[..size]);
+
+        return Ok(size);
+    }
+
+    // Restart the decompression from the beginning of the file
+    fn reset(&mut self) -> io::Result<()> {
+        let mut reader: File = self.file.try_clone()?;
+        reader.seek(io::SeekFrom::Start(0))?;
+
+        self.decoder = match self.compression {
+            Compression::Gzip => Some(Box::new(MultiGzDecoder::new(reader))),
+            Compression::Zstd => Some(Box::new(ZstdDecoder::new(reader)?)),
+            Compression::None => None,
+        };
+        self.window.clear();
+        self.window_start = 0;
+
+        return Ok(());
+    }
+}
+
+fn decode(decoder: &mut Option<Box<Read>>, buffer: &mut [u8]) -> io::Result<usize> {
+    return match *decoder {
+        Some(ref mut decoder) => decoder.read(buffer),
+        None => Ok(0),
+    };
+}
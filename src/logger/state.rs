@@ -35,9 +35,12 @@ use std::fs::File;
 use std::fs::OpenOptions;
 use std::path::Path;
 use std::io::prelude::*;
+use std::io;
 use std::io::BufWriter;
 use serde_json;
 use sha1;
+use logger::files::{FileId};
+use error::Error;
 
 const DEFAULT_STATES_PATH: &'static str = "/usr/share/awatchlog/states";
 
@@ -45,44 +48,84 @@ const DEFAULT_STATES_PATH: &'static str = "/usr/share/awatchlog/states";
 pub struct State {
     pub token: String,
     pub offset: u64,
+    /// Device and inode of the file the offset applies to
+    #[serde(default)]
+    pub device: Option<u64>,
+    #[serde(default)]
+    pub inode: Option<u64>,
+    /// SHA-1 of the first bytes of the file the offset applies to
+    #[serde(default)]
+    pub fingerprint: Option<String>,
 }
 
-pub struct Error {
-    pub code: u64,
-    pub message: String,
+impl State {
+    /// Tell if the state applies to the file
+    ///
+    /// A file is recognized by its fingerprint when both are known, a file
+    /// moved or copied gets a new inode and an inode may be recycled for
+    /// another file. Its device and inode are compared otherwise. When the
+    /// state holds neither, for instance written by an older version, the
+    /// file is assumed to be the same.
+    pub fn matches(&self, file_id: FileId, fingerprint: Option<&String>) -> bool {
+        if let (Some(expected), Some(current)) = (self.fingerprint.as_ref(), fingerprint) {
+            return expected == current;
+        }
+
+        if let (Some(device), Some(inode)) = (self.device, self.inode) {
+            return (FileId { device, inode }) == file_id;
+        }
+
+        return true;
+    }
 }
 
-pub fn load(logfile: String, states_dir: Option<String>) -> Result<State, Error> {
-    let state_path_dir = get_state_file_path(logfile, states_dir);
+/// Load the state of the logfile, None when it has never been saved
+pub fn load(logfile: String, states_dir: Option<String>) -> Result<Option<State>, Error> {
+    let state_path_dir = get_state_file_path(logfile, states_dir)?;
     let state_path = Path::new(&state_path_dir);
 
-    return match File::open(state_path) {
-        Ok(file) => Ok(serde_json::from_reader(file).unwrap()),
-        Err(_) => Err(Error {
-            code: 0,
-            message: "State file not found".to_string()
-        })
+    let file = match File::open(state_path) {
+        Ok(file) => file,
+        Err(ref why) if io::ErrorKind::NotFound == why.kind() => return Ok(None),
+        Err(why) => return Err(Error::State(format!("Cannot open state file {} : {}", state_path_dir, why))),
+    };
+
+    return match serde_json::from_reader(file) {
+        Ok(state) => Ok(Some(state)),
+        Err(why) => Err(Error::State(format!("Invalid state file {} : {}", state_path_dir, why))),
     };
 }
 
-pub fn save(logfile: String, states_dir: Option<String>, state: State) {
-    let state_path_dir = get_state_file_path(logfile, states_dir);
+pub fn save(logfile: String, states_dir: Option<String>, state: State) -> Result<(), Error> {
+    let state_path_dir = get_state_file_path(logfile, states_dir)?;
     let state_json = json!(state);
     let json: String = state_json.to_string();
 
-    let file = OpenOptions::new()
+    let file = match OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(state_path_dir).unwrap();
+        .open(&state_path_dir) {
+        Ok(file) => file,
+        Err(why) => return Err(Error::State(format!("Cannot open state file {} : {}", state_path_dir, why))),
+    };
 
-    {
-        let mut writer = BufWriter::new(file);
-        writer.write_all(json.as_bytes());
-    }
+    let mut writer = BufWriter::new(file);
+    return writer.write_all(json.as_bytes())
+        .and_then(|_| writer.flush())
+        .map_err(|why| Error::State(format!("Cannot write state file {} : {}", state_path_dir, why)));
+}
+
+/// Dead-letter file of the logfile, kept next to its state
+pub fn get_dead_letter_file_path(logfile: String, states_dir: Option<String>) -> Result<String, Error> {
+    return get_file_path(logfile, states_dir, "rejected.jsonl");
 }
 
-fn get_state_file_path(logfile: String, state: Option<String>) -> String {
+fn get_state_file_path(logfile: String, states_dir: Option<String>) -> Result<String, Error> {
+    return get_file_path(logfile, states_dir, "json");
+}
+
+fn get_file_path(logfile: String, state: Option<String>, extension: &str) -> Result<String, Error> {
     let state_path_dir: String = if let Some(custom_state_path) = state {
         custom_state_path
     } else {
@@ -93,18 +136,59 @@ fn get_state_file_path(logfile: String, state: Option<String>) -> String {
 
     if false == state_path.exists() {
         println!("States path not exists, try to create at: {}", state_path_dir);
-        match fs::create_dir(state_path) {
-            Ok(_) => {},
-            Err(_) => panic!("Cannot create sates path at {}", state_path_dir)
+        // Tolerate the watcher of another file creating it at the same time
+        if let Err(why) = fs::create_dir_all(state_path) {
+            return Err(Error::State(format!("Cannot create states path at {} : {}", state_path_dir, why)));
         }
     }
 
     let mut file_path_sha1 = sha1::Sha1::new();
     file_path_sha1.update(logfile.as_bytes());
 
-    return format!(
-        "{}/{}.json",
+    return Ok(format!(
+        "{}/{}.{}",
         state_path_dir,
-        file_path_sha1.digest().to_string()
-    );
-}
\ No newline at end of file
+        file_path_sha1.digest().to_string(),
+        extension
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(file_id: Option<FileId>, fingerprint: Option<&str>) -> State {
+        return State {
+            token: "49600000000001".to_string(),
+            offset: 42,
+            device: file_id.map(|id| id.device),
+            inode: file_id.map(|id| id.inode),
+            fingerprint: fingerprint.map(|value| value.to_string()),
+        };
+    }
+
+    const FILE: FileId = FileId { device: 2049, inode: 1001 };
+    const MOVED: FileId = FileId { device: 2049, inode: 2002 };
+
+    #[test]
+    fn fingerprint_wins_over_inode() {
+        let saved = state(Some(FILE), Some("abc"));
+
+        assert!(saved.matches(MOVED, Some(&"abc".to_string())));
+        assert!(!saved.matches(FILE, Some(&"def".to_string())));
+    }
+
+    #[test]
+    fn inode_decides_without_fingerprint() {
+        let saved = state(Some(FILE), None);
+
+        assert!(saved.matches(FILE, Some(&"abc".to_string())));
+        assert!(!saved.matches(MOVED, None));
+        assert!(!state(Some(FILE), Some("abc")).matches(MOVED, None));
+    }
+
+    #[test]
+    fn older_state_matches_any_file() {
+        assert!(state(None, None).matches(MOVED, None));
+    }
+}
@@ -0,0 +1,219 @@
+// Package: AWatchLog
+//
+// BSD 3-Clause License
+//
+// Copyright (c) 2018, Pierre Tomasina
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+// list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// * Neither the name of the copyright holder nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use chrono::{DateTime, Datelike, Duration, Local, Utc};
+use chrono::format::{self, Parsed, StrftimeItems};
+use config::configuration::{LogTimeZone};
+
+/// Parse the timestamp at the beginning of a log line
+///
+/// Return the timestamp in milliseconds since epoch, or None if the line
+/// does not start with a date matching the chrono `datetime_format`.
+///
+/// Dates without offset are read in `time_zone`. Dates without year
+/// (syslog style) are given the current year, or the previous one if that
+/// would put them in the future, e.g. a line written on Dec 31 and read
+/// on Jan 1.
+pub fn parse(
+    line: &str,
+    datetime_format: &str,
+    time_zone: &LogTimeZone,
+    now: DateTime<Utc>
+) -> Option<i64> {
+    if datetime_format.is_empty() {
+        return None;
+    }
+
+    let mut parsed = Parsed::new();
+
+    // The rest of the line follows the timestamp, so chrono will always
+    // complain about trailing input. Only the fields filled matter.
+    let _ = format::parse(&mut parsed, line, StrftimeItems::new(datetime_format));
+
+    if parsed.timestamp.is_some() || parsed.year.is_some()
+        || parsed.year_div_100.is_some() || parsed.year_mod_100.is_some()
+        || parsed.isoyear.is_some() {
+        return to_millis(&parsed, time_zone);
+    }
+
+    let now_millis: i64 = now.timestamp() * 1000;
+    let current_year: i32 = current_year(time_zone, now);
+
+    // Allow a small clock skew between the writer and the agent
+    let skew: i64 = Duration::days(1).num_milliseconds();
+
+    for year in &[current_year, current_year - 1] {
+        let mut with_year: Parsed = parsed.clone();
+        if with_year.set_year(*year as i64).is_err() {
+            return None;
+        }
+
+        if let Some(millis) = to_millis(&with_year, time_zone) {
+            if millis <= now_millis + skew {
+                return Some(millis);
+            }
+        }
+    }
+
+    return None;
+}
+
+fn to_millis(parsed: &Parsed, time_zone: &LogTimeZone) -> Option<i64> {
+    // An offset written in the line takes precedence over the configuration
+    if let Some(offset) = parsed.offset {
+        return match parsed.to_naive_datetime_with_offset(offset) {
+            Ok(datetime) => {
+                let utc = datetime - Duration::seconds(offset as i64);
+                Some(utc.timestamp() * 1000 + utc.timestamp_subsec_millis() as i64)
+            },
+            Err(_) => None,
+        };
+    }
+
+    return match parsed.to_naive_datetime_with_offset(0) {
+        Ok(datetime) => {
+            if parsed.timestamp.is_some() {
+                // Epoch timestamps are always UTC
+                Some(datetime.timestamp() * 1000 + datetime.timestamp_subsec_millis() as i64)
+            } else {
+                time_zone.timestamp_millis(&datetime)
+            }
+        },
+        Err(_) => None,
+    };
+}
+
+fn current_year(time_zone: &LogTimeZone, now: DateTime<Utc>) -> i32 {
+    return match *time_zone {
+        LogTimeZone::Utc => now.year(),
+        LogTimeZone::Local => now.with_timezone(&Local).year(),
+        LogTimeZone::Fixed(offset) => now.with_timezone(&offset).year(),
+        LogTimeZone::Named(tz) => now.with_timezone(&tz).year(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+    use chrono_tz::Europe::Paris;
+    use config::configuration::LogTimeZone;
+    use super::parse;
+
+    const SYSLOG_FORMAT: &'static str = "%b %d %H:%M:%S";
+    const ISO_FORMAT: &'static str = "%Y-%m-%d %H:%M:%S";
+
+    fn millis(datetime: DateTime<Utc>) -> Option<i64> {
+        return Some(datetime.timestamp() * 1000);
+    }
+
+    #[test]
+    fn line_without_year_read_on_new_year_is_from_last_year() {
+        let now = Utc.ymd(2026, 1, 1).and_hms(0, 0, 5);
+
+        assert_eq!(
+            millis(Utc.ymd(2025, 12, 31).and_hms(23, 59, 58)),
+            parse("Dec 31 23:59:58 host app: last line", SYSLOG_FORMAT, &LogTimeZone::Utc, now)
+        );
+        assert_eq!(
+            millis(Utc.ymd(2026, 1, 1).and_hms(0, 0, 1)),
+            parse("Jan 01 00:00:01 host app: first line", SYSLOG_FORMAT, &LogTimeZone::Utc, now)
+        );
+    }
+
+    #[test]
+    fn line_without_year_slightly_ahead_is_kept_in_the_current_year() {
+        let now = Utc.ymd(2026, 3, 10).and_hms(12, 0, 0);
+
+        // Within the allowed clock skew
+        assert_eq!(
+            millis(Utc.ymd(2026, 3, 10).and_hms(18, 0, 0)),
+            parse("Mar 10 18:00:00 host app: ahead", SYSLOG_FORMAT, &LogTimeZone::Utc, now)
+        );
+
+        // Too far ahead to be this year
+        assert_eq!(
+            millis(Utc.ymd(2025, 3, 12).and_hms(12, 0, 0)),
+            parse("Mar 12 12:00:00 host app: last year", SYSLOG_FORMAT, &LogTimeZone::Utc, now)
+        );
+    }
+
+    #[test]
+    fn fixed_zone_applies_unless_the_line_has_an_offset() {
+        let east = LogTimeZone::Fixed(FixedOffset::east(2 * 3600));
+        let now = Utc.ymd(2026, 3, 10).and_hms(12, 0, 0);
+
+        assert_eq!(
+            millis(Utc.ymd(2026, 3, 10).and_hms(10, 0, 0)),
+            parse("2026-03-10 12:00:00 app: local", ISO_FORMAT, &east, now)
+        );
+        assert_eq!(
+            millis(Utc.ymd(2026, 3, 10).and_hms(7, 0, 0)),
+            parse("2026-03-10 12:00:00 +0500 app: offset", "%Y-%m-%d %H:%M:%S %z", &east, now)
+        );
+    }
+
+    #[test]
+    fn year_is_the_one_of_the_zone() {
+        // Already the new year two hours east of UTC
+        let east = LogTimeZone::Fixed(FixedOffset::east(2 * 3600));
+        let now = Utc.ymd(2025, 12, 31).and_hms(23, 30, 0);
+
+        assert_eq!(
+            millis(Utc.ymd(2025, 12, 31).and_hms(23, 0, 0)),
+            parse("Jan 01 01:00:00 app: new year", SYSLOG_FORMAT, &east, now)
+        );
+    }
+
+    #[test]
+    fn named_zone_follows_daylight_saving_time() {
+        let paris = LogTimeZone::Named(Paris);
+        let now = Utc.ymd(2026, 12, 1).and_hms(0, 0, 0);
+
+        assert_eq!(
+            millis(Utc.ymd(2026, 1, 15).and_hms(11, 0, 0)),
+            parse("2026-01-15 12:00:00 app: winter", ISO_FORMAT, &paris, now)
+        );
+        assert_eq!(
+            millis(Utc.ymd(2026, 7, 15).and_hms(10, 0, 0)),
+            parse("2026-07-15 12:00:00 app: summer", ISO_FORMAT, &paris, now)
+        );
+
+        // Skipped when the clocks go forward
+        assert_eq!(None, parse("2026-03-29 02:30:00 app: gap", ISO_FORMAT, &paris, now));
+
+        // Seen twice when they go back, the first one is taken
+        assert_eq!(
+            millis(Utc.ymd(2026, 10, 25).and_hms(0, 30, 0)),
+            parse("2026-10-25 02:30:00 app: fold", ISO_FORMAT, &paris, now)
+        );
+    }
+}